@@ -0,0 +1,316 @@
+use std::{fmt, ops::Range};
+
+use crate::error::{Error, Result};
+
+/// Lists and dictionaries nested deeper than this are refused, so that hostile
+/// input cannot exhaust the stack
+const MAX_DEPTH: usize = 256;
+
+/// A bencoded value, borrowing its strings from the input it was parsed from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Node<'a>>),
+    /// Entries in the order of the input, which is not necessarily sorted
+    Dict(Vec<(&'a [u8], Node<'a>)>),
+}
+
+/// A parsed value and where it is in the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    pub value: Value<'a>,
+    /// Byte range of the value in the input
+    pub span: Range<usize>,
+    /// The bytes that encode the value, exactly as they appear in the input
+    pub raw: &'a [u8],
+}
+
+/// Why and where parsing stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset in the input
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse `input`, which must hold exactly one bencoded value
+pub fn parse(input: &[u8]) -> std::result::Result<Node<'_>, ParseError> {
+    let node = parse_prefix(input)?;
+    if node.span.end != input.len() {
+        return Err(ParseError {
+            offset: node.span.end,
+            message: "trailing data after the value".to_string(),
+        });
+    }
+    Ok(node)
+}
+
+/// Parse the bencoded value at the start of `input`, ignoring whatever follows it.
+/// Needed where raw data follows a value, as in ut_metadata data messages.
+pub fn parse_prefix(input: &[u8]) -> std::result::Result<Node<'_>, ParseError> {
+    Cursor { input, position: 0 }.node(0)
+}
+
+impl<'a> Node<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self.value {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.value {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Node<'a>]> {
+        match &self.value {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&[(&'a [u8], Node<'a>)]> {
+        match &self.value {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// The value of `key` if this is a dictionary that has it
+    pub fn get(&self, key: &str) -> Option<&Node<'a>> {
+        self.as_dict()?
+            .iter()
+            .find(|(k, _)| *k == key.as_bytes())
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<i64> {
+        self.get(key)
+            .and_then(Node::as_int)
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Result<&'a [u8]> {
+        self.get(key)
+            .and_then(Node::as_bytes)
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&'a str> {
+        self.get(key)
+            .and_then(Node::as_str)
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+
+    pub fn get_list(&self, key: &str) -> Result<&[Node<'a>]> {
+        self.get(key)
+            .and_then(Node::as_list)
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+
+    /// The dictionary node of `key`, see [`Node::get`] to look into it
+    pub fn get_dict(&self, key: &str) -> Result<&Node<'a>> {
+        self.get(key)
+            .filter(|node| node.as_dict().is_some())
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+}
+
+struct Cursor<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, offset: usize, message: impl Into<String>) -> ParseError {
+        ParseError {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    fn node(&mut self, depth: usize) -> std::result::Result<Node<'a>, ParseError> {
+        let start = self.position;
+        let value = match self.input.get(start) {
+            Some(b'i') => {
+                self.position += 1;
+                let digits = self.until(b'e', "unterminated integer")?;
+                Value::Int(parse_int(digits).ok_or_else(|| self.error(start, "invalid integer"))?)
+            }
+            Some(b'0'..=b'9') => Value::Bytes(self.bytes()?),
+            Some(b'l' | b'd') if depth == MAX_DEPTH => {
+                return Err(self.error(start, "values nested too deeply"))
+            }
+            Some(b'l') => {
+                self.position += 1;
+                let mut list = vec![];
+                while !self.end_of_container()? {
+                    list.push(self.node(depth + 1)?);
+                }
+                Value::List(list)
+            }
+            Some(b'd') => {
+                self.position += 1;
+                let mut dict = vec![];
+                while !self.end_of_container()? {
+                    if !self.input[self.position].is_ascii_digit() {
+                        return Err(self.error(self.position, "dictionary key must be a string"));
+                    }
+                    let key = self.bytes()?;
+                    dict.push((key, self.node(depth + 1)?));
+                }
+                Value::Dict(dict)
+            }
+            Some(byte) => return Err(self.error(start, format!("unexpected byte {byte:#04x}"))),
+            None => return Err(self.error(start, "unexpected end of input")),
+        };
+
+        Ok(Node {
+            value,
+            span: start..self.position,
+            raw: &self.input[start..self.position],
+        })
+    }
+
+    /// A byte string, `<length>:<contents>`
+    fn bytes(&mut self) -> std::result::Result<&'a [u8], ParseError> {
+        let start = self.position;
+        let length = self.until(b':', "missing string length separator")?;
+        let length =
+            parse_length(length).ok_or_else(|| self.error(start, "invalid string length"))?;
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.input.len())
+            .ok_or_else(|| self.error(start, "string runs past the end of the input"))?;
+        let bytes = &self.input[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// The bytes up to `terminator`, which is skipped
+    fn until(
+        &mut self,
+        terminator: u8,
+        message: &str,
+    ) -> std::result::Result<&'a [u8], ParseError> {
+        let start = self.position;
+        let length = self.input[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or_else(|| self.error(start, message))?;
+        self.position += length + 1;
+        Ok(&self.input[start..start + length])
+    }
+
+    /// Consume the `e` closing a list or dictionary if it is next
+    fn end_of_container(&mut self) -> std::result::Result<bool, ParseError> {
+        match self.input.get(self.position) {
+            Some(b'e') => {
+                self.position += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(self.error(self.position, "unterminated list or dictionary")),
+        }
+    }
+}
+
+/// Decimal digits without leading zeros, the only canonical form
+fn parse_length(digits: &[u8]) -> Option<usize> {
+    match digits {
+        [b'0'] => Some(0),
+        [b'1'..=b'9', rest @ ..] if rest.iter().all(u8::is_ascii_digit) => {
+            std::str::from_utf8(digits).ok()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// An integer, negative zero is not allowed
+fn parse_int(digits: &[u8]) -> Option<i64> {
+    match digits {
+        [b'-', b'0', ..] => None,
+        [b'-', rest @ ..] => {
+            parse_length(rest)?;
+            std::str::from_utf8(digits).ok()?.parse().ok()
+        }
+        _ => {
+            parse_length(digits)?;
+            std::str::from_utf8(digits).ok()?.parse().ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_span_of_every_value() {
+        let input = b"d3:food3:bari-7ee4:listl4:spami0eee";
+        let root = parse(input).unwrap();
+        assert_eq!(root.span, 0..input.len());
+
+        let foo = root.get("foo").unwrap();
+        assert_eq!(foo.raw, b"d3:bari-7ee");
+        assert_eq!(foo.span, 6..17);
+        assert_eq!(foo.get_int("bar").unwrap(), -7);
+
+        let list = root.get_list("list").unwrap();
+        assert_eq!(list[0].as_str(), Some("spam"));
+        assert_eq!(list[1].raw, b"i0e");
+    }
+
+    #[test]
+    fn keeps_dictionaries_in_input_order() {
+        let root = parse(b"d1:bi1e1:ai2ee").unwrap();
+        let keys: Vec<&[u8]> = root.as_dict().unwrap().iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, [b"b".as_ref(), b"a".as_ref()]);
+        assert_eq!(root.get_int("a").unwrap(), 2);
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let error = |input: &[u8]| parse(input).unwrap_err();
+
+        assert_eq!(error(b"l4:spami42e").offset, 11);
+        assert_eq!(error(b"i042e").offset, 0);
+        assert_eq!(error(b"i-0e").offset, 0);
+        assert_eq!(error(b"d3:fooi1ei2ei3ee").offset, 9);
+        assert_eq!(error(b"l5:abce").offset, 1);
+        assert_eq!(error(b"i1ejunk").offset, 3);
+        assert_eq!(
+            error(b"li1ex").to_string(),
+            "unexpected byte 0x78 at byte 4"
+        );
+
+        let deep = [vec![b'l'; MAX_DEPTH + 1], vec![b'e'; MAX_DEPTH + 1]].concat();
+        assert_eq!(error(&deep).offset, MAX_DEPTH);
+    }
+
+    #[test]
+    fn parses_a_prefix() {
+        let node = parse_prefix(b"d8:msg_typei1e5:piecei0eeRAW DATA").unwrap();
+        assert_eq!(node.span, 0..25);
+        assert_eq!(node.get_int("msg_type").unwrap(), 1);
+    }
+}
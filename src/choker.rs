@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+/// How often the choker recomputes which peers to unchoke
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves on to another peer
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of regular unchoke slots, the optimistic unchoke comes on top
+pub const REGULAR_SLOTS: usize = 4;
+
+/// Byte counters of one peer connection, updated by the connection and read by the choker
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+impl PeerStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Block data received from the peer
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Block data sent to the peer
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+}
+
+/// Peers to unchoke and to choke after a choker round
+#[derive(Debug, PartialEq)]
+pub struct ChokeUpdate<K> {
+    pub unchoke: Vec<K>,
+    pub choke: Vec<K>,
+}
+
+struct ChokerPeer {
+    stats: Arc<PeerStats>,
+    interested: bool,
+    unchoked: bool,
+    /// Bytes per second over the last round
+    rate: f64,
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+/// The BEP 3 tit-for-tat choker.
+/// Every round the peers with the best rate get the regular unchoke slots: the rate is
+/// their download rate to us while leeching, and our upload rate to them while seeding.
+/// Faster peers that aren't interested are unchoked as well without taking a slot, so
+/// they can start right away once they become interested. One more peer is unchoked
+/// optimistically, rotating every 30 seconds, to discover better partners.
+pub struct Choker<K> {
+    regular_slots: usize,
+    seeding: bool,
+    peers: HashMap<K, ChokerPeer>,
+    optimistic: Option<K>,
+    optimistic_since: Option<Instant>,
+    last_round: Option<Instant>,
+}
+
+impl<K> Choker<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(seeding: bool) -> Self {
+        Self {
+            regular_slots: REGULAR_SLOTS,
+            seeding,
+            peers: HashMap::new(),
+            optimistic: None,
+            optimistic_since: None,
+            last_round: None,
+        }
+    }
+
+    pub fn with_regular_slots(mut self, regular_slots: usize) -> Self {
+        self.regular_slots = regular_slots;
+        self
+    }
+
+    /// Switch to ranking peers by upload rate once the download completes
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
+    pub fn add_peer(&mut self, key: K, stats: Arc<PeerStats>) {
+        let (last_downloaded, last_uploaded) = (stats.downloaded(), stats.uploaded());
+        self.peers.insert(
+            key,
+            ChokerPeer {
+                stats,
+                interested: false,
+                unchoked: false,
+                rate: 0.0,
+                last_downloaded,
+                last_uploaded,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, key: &K) {
+        self.peers.remove(key);
+        if self.optimistic.as_ref() == Some(key) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn is_unchoked(&self, key: &K) -> bool {
+        self.peers.get(key).is_some_and(|peer| peer.unchoked)
+    }
+
+    /// Record interest. A newly interested peer is unchoked right away if a slot is free,
+    /// otherwise it waits for the next round. Returns true if the peer should be unchoked now.
+    pub fn set_interested(&mut self, key: &K, interested: bool) -> bool {
+        let used = self
+            .peers
+            .values()
+            .filter(|peer| peer.unchoked && peer.interested)
+            .count();
+
+        let Some(peer) = self.peers.get_mut(key) else {
+            return false;
+        };
+        peer.interested = interested;
+
+        if interested && !peer.unchoked && used < self.regular_slots + 1 {
+            peer.unchoked = true;
+            return true;
+        }
+        false
+    }
+
+    /// Run a choking round, to be called every `RECHOKE_INTERVAL`
+    pub fn recompute(&mut self, now: Instant) -> ChokeUpdate<K> {
+        self.update_rates(now);
+
+        // Rank all peers by rate, fastest first
+        let mut ranked: Vec<(&K, &ChokerPeer)> = self.peers.iter().collect();
+        ranked.sort_by(|a, b| b.1.rate.total_cmp(&a.1.rate));
+
+        let mut unchoke: Vec<K> = vec![];
+        let mut slots = 0;
+        for (key, peer) in ranked {
+            if slots >= self.regular_slots {
+                break;
+            }
+            unchoke.push(key.clone());
+            if peer.interested {
+                slots += 1;
+            }
+        }
+
+        self.rotate_optimistic(now, &unchoke);
+        if let Some(optimistic) = &self.optimistic {
+            if !unchoke.contains(optimistic) {
+                unchoke.push(optimistic.clone());
+            }
+        }
+
+        let mut update = ChokeUpdate {
+            unchoke: vec![],
+            choke: vec![],
+        };
+        for (key, peer) in self.peers.iter_mut() {
+            let unchoked = unchoke.contains(key);
+            if unchoked && !peer.unchoked {
+                update.unchoke.push(key.clone());
+            } else if !unchoked && peer.unchoked {
+                update.choke.push(key.clone());
+            }
+            peer.unchoked = unchoked;
+        }
+
+        update
+    }
+
+    fn update_rates(&mut self, now: Instant) {
+        let elapsed = self
+            .last_round
+            .map_or(RECHOKE_INTERVAL, |last| now.duration_since(last))
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        self.last_round = Some(now);
+
+        for peer in self.peers.values_mut() {
+            let (downloaded, uploaded) = (peer.stats.downloaded(), peer.stats.uploaded());
+            let delta = if self.seeding {
+                uploaded - peer.last_uploaded
+            } else {
+                downloaded - peer.last_downloaded
+            };
+            peer.rate = delta as f64 / elapsed;
+            peer.last_downloaded = downloaded;
+            peer.last_uploaded = uploaded;
+        }
+    }
+
+    fn rotate_optimistic(&mut self, now: Instant, regular: &[K]) {
+        // A peer that earned a regular slot no longer needs the optimistic one
+        let current_is_valid = self.optimistic.as_ref().is_some_and(|key| {
+            !regular.contains(key) && self.peers.get(key).is_some_and(|peer| peer.interested)
+        });
+        let due = self
+            .optimistic_since
+            .is_none_or(|since| now.duration_since(since) >= OPTIMISTIC_INTERVAL);
+
+        if current_is_valid && !due {
+            return;
+        }
+
+        self.optimistic = self
+            .peers
+            .iter()
+            .filter(|(key, peer)| peer.interested && !regular.contains(key))
+            .map(|(key, _)| key.clone())
+            .choose(&mut rand::thread_rng());
+        self.optimistic_since = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn choker_with_peers(rates: &[u64], seeding: bool) -> (Choker<usize>, Vec<Arc<PeerStats>>) {
+        let mut choker = Choker::new(seeding).with_regular_slots(2);
+        let stats: Vec<Arc<PeerStats>> = rates.iter().map(|_| PeerStats::new()).collect();
+        for (key, peer_stats) in stats.iter().enumerate() {
+            choker.add_peer(key, peer_stats.clone());
+            choker.set_interested(&key, true);
+        }
+        for (peer_stats, &bytes) in stats.iter().zip(rates) {
+            if seeding {
+                peer_stats.record_upload(bytes);
+            } else {
+                peer_stats.record_download(bytes);
+            }
+        }
+        (choker, stats)
+    }
+
+    #[test]
+    fn unchokes_fastest_peers_plus_one_optimistic() {
+        let (mut choker, _) = choker_with_peers(&[100, 5000, 300, 9000, 10], false);
+        let start = Instant::now();
+        choker.recompute(start);
+
+        // Peers 3 and 1 have the best download rates
+        assert!(choker.is_unchoked(&3));
+        assert!(choker.is_unchoked(&1));
+        let optimistic = choker.optimistic.unwrap();
+        assert!([0, 2, 4].contains(&optimistic));
+        assert!(choker.is_unchoked(&optimistic));
+        assert_eq!(
+            choker.peers.values().filter(|peer| peer.unchoked).count(),
+            3
+        );
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_rate() {
+        let (mut choker, stats) = choker_with_peers(&[0, 0, 0], true);
+        stats[2].record_download(1_000_000);
+        stats[0].record_upload(500);
+        stats[1].record_upload(700);
+
+        choker.recompute(Instant::now());
+        assert!(choker.is_unchoked(&0));
+        assert!(choker.is_unchoked(&1));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_30_seconds() {
+        let (mut choker, stats) = choker_with_peers(&[9000, 8000, 0, 0, 0, 0, 0, 0], false);
+        let start = Instant::now();
+        choker.recompute(start);
+        let first = choker.optimistic.unwrap();
+
+        // The same optimistic peer survives regular rounds
+        for round in 1..3 {
+            for peer_stats in &stats[..2] {
+                peer_stats.record_download(10_000);
+            }
+            choker.recompute(start + RECHOKE_INTERVAL * round);
+            assert_eq!(choker.optimistic, Some(first));
+        }
+
+        // It is replaced after 30 seconds, with 6 candidates it may be picked again
+        let mut rotated = false;
+        for round in 3..30 {
+            for peer_stats in &stats[..2] {
+                peer_stats.record_download(10_000);
+            }
+            choker.recompute(start + RECHOKE_INTERVAL * round);
+            rotated |= choker.optimistic != Some(first);
+        }
+        assert!(rotated);
+    }
+
+    #[test]
+    fn peers_losing_interest_get_choked() {
+        let (mut choker, stats) = choker_with_peers(&[1000, 2000, 3000], false);
+        let start = Instant::now();
+        choker.recompute(start);
+        assert_eq!(choker.optimistic, Some(0));
+        assert!((0..3).all(|key| choker.is_unchoked(&key)));
+
+        // Peer 0 is the slowest and no longer interested, so it loses the optimistic unchoke
+        choker.set_interested(&0, false);
+        stats[1].record_download(2000);
+        stats[2].record_download(3000);
+        let update = choker.recompute(start + RECHOKE_INTERVAL);
+        assert_eq!(update.choke, vec![0]);
+        assert!(update.unchoke.is_empty());
+        assert_eq!(choker.optimistic, None);
+    }
+
+    #[test]
+    fn interested_peers_are_unchoked_while_slots_are_free() {
+        let mut choker = Choker::new(false).with_regular_slots(1);
+        for key in 0..3 {
+            choker.add_peer(key, PeerStats::new());
+        }
+        // One regular slot plus the optimistic one
+        assert!(choker.set_interested(&0, true));
+        assert!(choker.set_interested(&1, true));
+        assert!(!choker.set_interested(&2, true));
+        assert!(!choker.set_interested(&7, true));
+    }
+}
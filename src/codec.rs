@@ -0,0 +1,166 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    error::{Error, Result},
+    peer_message::Message,
+};
+
+/// Largest frame accepted from a peer unless configured otherwise.
+/// A PIECE message carries a 16 KiB block, bitfields of very large torrents and
+/// extension messages are the only other frames that come close.
+pub const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+/// A peer connection after the handshake, a stream and sink of peer messages
+pub type PeerStream = Framed<TcpStream, PeerCodec>;
+
+/// Frames peer wire messages as `<length prefix><message ID><payload>`
+#[derive(Debug, Clone)]
+pub struct PeerCodec {
+    max_frame_size: usize,
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl PeerCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject frames announcing a length above `max_frame_size` instead of buffering them
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Wrap a handshaken connection into a framed message stream
+    pub fn framed(stream: TcpStream) -> PeerStream {
+        Framed::new(stream, PeerCodec::new())
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if length > self.max_frame_size {
+            return Err(Error::protocol(format!(
+                "frame of {length} bytes exceeds the maximum of {}",
+                self.max_frame_size
+            )));
+        }
+
+        if src.len() < 4 + length {
+            // Wait for the rest of the frame, reserving room for it up front
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+
+        // The payload shares the read buffer, PIECE blocks are not copied
+        let mut frame = src.split_to(length).freeze();
+        let id = frame.get_u8();
+        Message::from_parts(id, frame).map(Some)
+    }
+}
+
+impl Encoder<Message> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<()> {
+        let Some(id) = message.id() else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+
+        match &message {
+            // Avoid building an intermediate payload for the large messages
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.reserve(13 + block.len());
+                dst.put_u32(9 + block.len() as u32);
+                dst.put_u8(id as u8);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            message => {
+                let payload = message.payload();
+                dst.reserve(5 + payload.len());
+                dst.put_u32(1 + payload.len() as u32);
+                dst.put_u8(id as u8);
+                dst.extend_from_slice(&payload);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn decodes_partial_and_back_to_back_frames() {
+        let mut codec = PeerCodec::new();
+        let piece = Message::Piece {
+            index: 1,
+            begin: 0,
+            block: Bytes::from(vec![7u8; 100]),
+        };
+
+        let mut bytes = BytesMut::new();
+        codec.encode(Message::KeepAlive, &mut bytes).unwrap();
+        codec.encode(piece.clone(), &mut bytes).unwrap();
+        codec
+            .encode(Message::Have { index: 3 }, &mut bytes)
+            .unwrap();
+        let all = bytes.freeze();
+
+        // Feed the bytes in small chunks as they would arrive from the socket
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for chunk in all.chunks(7) {
+            src.extend_from_slice(chunk);
+            while let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        assert_eq!(
+            decoded,
+            vec![Message::KeepAlive, piece, Message::Have { index: 3 }]
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut codec = PeerCodec::new().with_max_frame_size(1024);
+        let mut src = BytesMut::from(&[0x7f, 0xff, 0xff, 0xff, 7][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
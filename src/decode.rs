@@ -1,6 +1,105 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt::Write, str::FromStr};
 
-use eyre::{eyre, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use eyre::eyre;
+
+use crate::{
+    bencode::{self, Node},
+    error::{Error, Result},
+};
+
+/// How byte strings are rendered in JSON. Strings that are valid UTF-8 appear as they
+/// are, others in the chosen encoding; [`ByteEncoding::parse`] undoes [`ByteEncoding::render`]
+/// exactly, so JSON produced with an encoding turns back into the same bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteEncoding {
+    /// `hex:` followed by the bytes in hex
+    #[default]
+    Hex,
+    /// `base64:` followed by the bytes in standard base64
+    Base64,
+    /// Invalid UTF-8 as `\xNN` escapes, backslashes doubled
+    Escaped,
+}
+
+impl ByteEncoding {
+    pub fn render(self, bytes: &[u8]) -> String {
+        let prefix = match self {
+            ByteEncoding::Hex => "hex:",
+            ByteEncoding::Base64 => "base64:",
+            ByteEncoding::Escaped => {
+                let mut rendered = String::new();
+                for chunk in bytes.utf8_chunks() {
+                    rendered.push_str(&chunk.valid().replace('\\', "\\\\"));
+                    for byte in chunk.invalid() {
+                        let _ = write!(rendered, "\\x{byte:02x}");
+                    }
+                }
+                return rendered;
+            }
+        };
+
+        // A string that looks encoded is encoded too, so that it is not mistaken for one
+        match std::str::from_utf8(bytes) {
+            Ok(string) if !string.starts_with(prefix) => string.to_string(),
+            _ => match self {
+                ByteEncoding::Base64 => format!("{prefix}{}", BASE64.encode(bytes)),
+                _ => format!("{prefix}{}", hex::encode(bytes)),
+            },
+        }
+    }
+
+    pub fn parse(self, rendered: &str) -> Result<Vec<u8>> {
+        match self {
+            ByteEncoding::Hex => match rendered.strip_prefix("hex:") {
+                Some(encoded) => hex::decode(encoded).map_err(Error::bencode),
+                None => Ok(rendered.as_bytes().to_vec()),
+            },
+            ByteEncoding::Base64 => match rendered.strip_prefix("base64:") {
+                Some(encoded) => BASE64.decode(encoded).map_err(Error::bencode),
+                None => Ok(rendered.as_bytes().to_vec()),
+            },
+            ByteEncoding::Escaped => {
+                let mut bytes = Vec::with_capacity(rendered.len());
+                let mut rest = rendered;
+                while let Some(escape) = rest.find('\\') {
+                    bytes.extend_from_slice(&rest.as_bytes()[..escape]);
+                    rest = &rest[escape..];
+                    if let Some(after) = rest.strip_prefix("\\\\") {
+                        bytes.push(b'\\');
+                        rest = after;
+                    } else if let Some(byte) = rest
+                        .get(2..4)
+                        .filter(|_| rest.starts_with("\\x"))
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    {
+                        bytes.push(byte);
+                        rest = &rest[4..];
+                    } else {
+                        return Err(Error::bencode(format!("invalid escape in {rendered:?}")));
+                    }
+                }
+                bytes.extend_from_slice(rest.as_bytes());
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl FromStr for ByteEncoding {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s {
+            "hex" => Ok(ByteEncoding::Hex),
+            "base64" => Ok(ByteEncoding::Base64),
+            "escaped" => Ok(ByteEncoding::Escaped),
+            _ => Err(eyre!(
+                "unknown byte encoding {s}, expected hex, base64 or escaped"
+            )),
+        }
+    }
+}
 
 pub struct Decoder;
 
@@ -24,56 +123,51 @@ impl Decoder {
     /// 4. Dictionaries: Encoded as d<contents>e. For example, a dictionary with keys "bar" and "foo" and values "spam" and 42, respectively, is encoded as d3:bar4:spam3:fooi42ee
     ///
     pub fn decode_bencoded_value(encoded_value: &str) -> Result<serde_json::Value> {
-        let value: serde_bencode::value::Value = serde_bencode::from_str(encoded_value)?;
-
-        Decoder::convert(value)
+        Decoder::decode_bencoded_bytes(encoded_value.as_bytes())
     }
 
     pub fn decode_bencoded_bytes(encoded_value: &[u8]) -> Result<serde_json::Value> {
-        let value: serde_bencode::value::Value = serde_bencode::from_bytes(encoded_value)?;
+        Decoder::to_json(encoded_value, ByteEncoding::default())
+    }
 
-        Decoder::convert(value)
+    /// The JSON view of a bencoded value, with byte strings rendered by `bytes`
+    pub fn to_json(encoded_value: &[u8], bytes: ByteEncoding) -> Result<serde_json::Value> {
+        Ok(Decoder::convert(&bencode::parse(encoded_value)?, bytes))
     }
 
-    fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value> {
-        match value {
-            serde_bencode::value::Value::Bytes(b) => {
+    /// Length of the bencoded value at the start of `encoded_value`.
+    /// Needed where raw data follows a value, as in ut_metadata data messages.
+    pub fn value_length(encoded_value: &[u8]) -> Result<usize> {
+        Ok(bencode::parse_prefix(encoded_value)?.raw.len())
+    }
+
+    fn convert(node: &Node, bytes: ByteEncoding) -> serde_json::Value {
+        match &node.value {
+            bencode::Value::Bytes(b) => {
                 // Decoding bencoded string
-                let string = String::from_utf8(b)?;
-                Ok(serde_json::Value::String(string))
+                serde_json::Value::String(bytes.render(b))
             }
 
-            serde_bencode::value::Value::Int(i) => {
+            bencode::Value::Int(i) => {
                 // Decoding bencoded integer
-                Ok(serde_json::Value::Number(serde_json::Number::from(i)))
+                serde_json::Value::Number(serde_json::Number::from(*i))
             }
 
-            serde_bencode::value::Value::List(l) => {
+            bencode::Value::List(l) => {
                 // Decoding bencoded list
-                let array = l
-                    .into_iter()
-                    .map(Decoder::convert)
-                    .collect::<Result<Vec<serde_json::Value>>>()?;
-
-                Ok(serde_json::Value::Array(array))
+                serde_json::Value::Array(l.iter().map(|v| Decoder::convert(v, bytes)).collect())
             }
 
-            serde_bencode::value::Value::Dict(d) => {
+            bencode::Value::Dict(d) => {
                 // Decoding bencoded dictionary
                 // Iterating over each key-value pair in the dictionary
                 let object = d
-                    .into_iter()
-                    .map(|(k, v)| {
-                        // For each pair, it converts the key to a string and recursively converts the value using this function
-                        let key = String::from_utf8(k)?;
+                    .iter()
+                    // For each pair, it renders the key and recursively converts the value using this function
+                    .map(|(k, v)| (bytes.render(k), Decoder::convert(v, bytes)))
+                    .collect();
 
-                        let value = Decoder::convert(v)?;
-
-                        Ok((key, value))
-                    })
-                    .collect::<Result<serde_json::Map<String, serde_json::Value>>>()?;
-
-                Ok(serde_json::Value::Object(object))
+                serde_json::Value::Object(object)
             }
         }
     }
@@ -88,7 +182,7 @@ impl Decoder {
 
                 _ => None,
             })
-            .ok_or(eyre!("Missing field: {}", key))
+            .ok_or_else(|| Error::MissingField(key.to_string()))
     }
 
     pub fn extract_bytes(
@@ -101,20 +195,33 @@ impl Decoder {
 
                 _ => None,
             })
-            .ok_or(eyre!("Missing field: {}", key))
+            .ok_or_else(|| Error::MissingField(key.to_string()))
     }
 
-    pub fn extract_dict(
+    pub fn extract_dict<'a>(
         key: &str,
-        d: &HashMap<Vec<u8>, serde_bencode::value::Value>,
-    ) -> Result<HashMap<Vec<u8>, serde_bencode::value::Value>> {
+        d: &'a HashMap<Vec<u8>, serde_bencode::value::Value>,
+    ) -> Result<&'a HashMap<Vec<u8>, serde_bencode::value::Value>> {
         d.get(key.as_bytes())
             .and_then(|v| match v {
-                serde_bencode::value::Value::Dict(d) => Some(d.clone()),
+                serde_bencode::value::Value::Dict(d) => Some(d),
 
                 _ => None,
             })
-            .ok_or(eyre!("Missing field: {}", key))
+            .ok_or_else(|| Error::MissingField(key.to_string()))
+    }
+
+    pub fn extract_list<'a>(
+        key: &str,
+        d: &'a HashMap<Vec<u8>, serde_bencode::value::Value>,
+    ) -> Result<&'a [serde_bencode::value::Value]> {
+        d.get(key.as_bytes())
+            .and_then(|v| match v {
+                serde_bencode::value::Value::List(l) => Some(l.as_slice()),
+
+                _ => None,
+            })
+            .ok_or_else(|| Error::MissingField(key.to_string()))
     }
 
     pub fn extract_int(
@@ -127,7 +234,7 @@ impl Decoder {
 
                 _ => None,
             })
-            .ok_or(eyre!("Missing field: {}", key))
+            .ok_or_else(|| Error::MissingField(key.to_string()))
     }
 }
 
@@ -163,4 +270,67 @@ mod tests {
         let decoded_value = Decoder::decode_bencoded_value(encoded_value).unwrap();
         assert_eq!(decoded_value, json!({"foo":"bar","hello":42}));
     }
+
+    #[test]
+    fn value_length_stops_at_end_of_value() {
+        let encoded_value = b"d8:msg_typei1e5:piecei0eeRAW DATA";
+        assert_eq!(Decoder::value_length(encoded_value).unwrap(), 25);
+        assert_eq!(Decoder::value_length(b"4:spamxx").unwrap(), 6);
+        assert!(Decoder::value_length(b"d3:foo").is_err());
+        assert!(Decoder::value_length(b"5:ab").is_err());
+    }
+
+    #[test]
+    fn errors_tell_bad_bencode_from_missing_fields() {
+        assert!(matches!(
+            Decoder::decode_bencoded_value("l5:hello"),
+            Err(Error::Bencode(_))
+        ));
+
+        let dictionary = HashMap::from([(b"name".to_vec(), serde_bencode::value::Value::Int(1))]);
+        assert!(matches!(
+            Decoder::extract_string("name", &dictionary),
+            Err(Error::MissingField(field)) if field == "name"
+        ));
+    }
+
+    #[test]
+    fn renders_binary_strings() {
+        let json =
+            Decoder::to_json(b"d4:name1:f6:pieces3:\x00\xff\x10e", ByteEncoding::Hex).unwrap();
+        assert_eq!(json, json!({"name": "f", "pieces": "hex:00ff10"}));
+
+        let json = Decoder::to_json(b"l3:\x00\xff\x10e", ByteEncoding::Base64).unwrap();
+        assert_eq!(json, json!(["base64:AP8Q"]));
+
+        let json = Decoder::to_json(b"d3:a\xffb1:\\e", ByteEncoding::Escaped).unwrap();
+        assert_eq!(json, json!({"a\\xffb": "\\\\"}));
+    }
+
+    #[test]
+    fn rendered_bytes_parse_back_unchanged() {
+        let samples: [&[u8]; 6] = [
+            b"plain",
+            b"",
+            b"\x00\xff\xfe",
+            b"hex:looks encoded",
+            b"base64:looks encoded",
+            b"back\\slash \\x41 and \xc3\x28",
+        ];
+        for encoding in [
+            ByteEncoding::Hex,
+            ByteEncoding::Base64,
+            ByteEncoding::Escaped,
+        ] {
+            for sample in samples {
+                let rendered = encoding.render(sample);
+                assert_eq!(encoding.parse(&rendered).unwrap(), sample, "{rendered}");
+            }
+        }
+
+        assert_eq!(ByteEncoding::Hex.render(b"hex:ab"), "hex:6865783a6162");
+        assert!(ByteEncoding::Escaped.parse("\\q").is_err());
+        assert!(ByteEncoding::Hex.parse("hex:zz").is_err());
+        assert!("octal".parse::<ByteEncoding>().is_err());
+    }
 }
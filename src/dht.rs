@@ -0,0 +1,1035 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Context, Result};
+use futures_util::future::join_all;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use tokio::{net::UdpSocket, sync::oneshot, time::timeout};
+
+use crate::{decode::Decoder, error::Error, Peers};
+
+/// Nodes per bucket and number of closest nodes a lookup converges on
+pub const K: usize = 8;
+
+/// Number of queries a lookup keeps in flight
+const ALPHA: usize = 3;
+
+/// Well-known nodes to join the mainline DHT through
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Time a node has to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A node not heard from for this long may be replaced by a new one
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Tokens are derived from a secret that changes every 5 minutes,
+/// tokens of the previous secret are still accepted
+const SECRET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Peers stored per info hash
+const MAX_PEERS_PER_TORRENT: usize = 200;
+
+/// Info hashes we store peers for, anyone can announce so this has to be bounded
+const MAX_TORRENTS: usize = 2000;
+
+/// Announced peers are forgotten unless they announce again within this time
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// Largest KRPC packet we read
+const MAX_PACKET_SIZE: usize = 2048;
+
+const ERROR_GENERIC: i64 = 201;
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A 160 bit node id, the same space as info hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    /// The XOR metric, compared as a big-endian number
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// Bucket of `other` in the routing table of `self`: the length of the common prefix
+    fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let zeros = distance
+            .iter()
+            .position(|&byte| byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+}
+
+/// A DHT node and the address it is reachable at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+impl NodeInfo {
+    /// Compact node info, the id followed by the compact peer format, 26 bytes per node
+    pub fn to_compact(nodes: &[NodeInfo]) -> Vec<u8> {
+        nodes
+            .iter()
+            .flat_map(|node| {
+                let mut compact = node.id.0.to_vec();
+                compact.extend(Peers(vec![SocketAddr::V4(node.addr)]).to_compact());
+                compact
+            })
+            .collect()
+    }
+
+    pub fn from_compact(compact: &[u8]) -> Result<Vec<NodeInfo>> {
+        if !compact.len().is_multiple_of(26) {
+            return Err(eyre!("compact node info has {} bytes", compact.len()));
+        }
+
+        compact
+            .chunks_exact(26)
+            .map(|chunk| {
+                Ok(NodeInfo {
+                    id: NodeId(chunk[..20].try_into()?),
+                    addr: compact_peers(&chunk[20..])?[0],
+                })
+            })
+            .collect()
+    }
+}
+
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+}
+
+/// Kademlia routing table of 160 buckets of up to `K` nodes each.
+/// Bucket `i` holds the nodes whose id shares exactly `i` leading bits with ours,
+/// so the table knows many nodes close to us and a few far away.
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| vec![]).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a node we heard from or refresh it.
+    /// A full bucket only takes the node if one of its entries went stale.
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let Some(index) = self.id.bucket_index(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            return true;
+        }
+
+        if bucket.len() < K {
+            bucket.push(Entry {
+                node,
+                last_seen: now,
+            });
+            return true;
+        }
+
+        let stale = bucket
+            .iter_mut()
+            .filter(|entry| now.duration_since(entry.last_seen) >= STALE_AFTER)
+            .min_by_key(|entry| entry.last_seen);
+        match stale {
+            Some(entry) => {
+                *entry = Entry {
+                    node,
+                    last_seen: now,
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The `count` known nodes closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|entry| entry.node)
+    }
+}
+
+/// A decoded KRPC message
+#[derive(Debug)]
+enum Krpc {
+    Query {
+        tid: Vec<u8>,
+        method: String,
+        args: HashMap<Vec<u8>, Value>,
+    },
+    Response {
+        tid: Vec<u8>,
+        values: HashMap<Vec<u8>, Value>,
+    },
+    Error {
+        tid: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Krpc {
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let Value::Dict(message) = serde_bencode::from_bytes(bytes)? else {
+            return Err(eyre!("KRPC message is not a dict"));
+        };
+        let tid = Decoder::extract_bytes("t", &message)?;
+
+        match Decoder::extract_string("y", &message)?.as_str() {
+            "q" => Ok(Krpc::Query {
+                tid,
+                method: Decoder::extract_string("q", &message)?,
+                args: Decoder::extract_dict("a", &message)?.clone(),
+            }),
+            "r" => Ok(Krpc::Response {
+                tid,
+                values: Decoder::extract_dict("r", &message)?.clone(),
+            }),
+            "e" => {
+                let error = Decoder::extract_list("e", &message)?;
+                let (code, message) = match error {
+                    [Value::Int(code), Value::Bytes(message)] => {
+                        (*code, String::from_utf8_lossy(message).into_owned())
+                    }
+                    _ => (ERROR_GENERIC, String::new()),
+                };
+                Ok(Krpc::Error { tid, code, message })
+            }
+            y => Err(eyre!("unknown KRPC message type {y}")),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let message = match self {
+            Krpc::Query { tid, method, args } => dict([
+                ("t", Value::Bytes(tid.clone())),
+                ("y", Value::Bytes(b"q".to_vec())),
+                ("q", Value::Bytes(method.as_bytes().to_vec())),
+                ("a", Value::Dict(args.clone())),
+            ]),
+            Krpc::Response { tid, values } => dict([
+                ("t", Value::Bytes(tid.clone())),
+                ("y", Value::Bytes(b"r".to_vec())),
+                ("r", Value::Dict(values.clone())),
+            ]),
+            Krpc::Error { tid, code, message } => dict([
+                ("t", Value::Bytes(tid.clone())),
+                ("y", Value::Bytes(b"e".to_vec())),
+                (
+                    "e",
+                    Value::List(vec![
+                        Value::Int(*code),
+                        Value::Bytes(message.as_bytes().to_vec()),
+                    ]),
+                ),
+            ]),
+        };
+        Ok(serde_bencode::to_bytes(&message)?)
+    }
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn into_dict(value: Value) -> HashMap<Vec<u8>, Value> {
+    match value {
+        Value::Dict(d) => d,
+        _ => HashMap::new(),
+    }
+}
+
+/// The parts of a response a lookup cares about
+#[derive(Debug)]
+struct Response {
+    id: NodeId,
+    nodes: Vec<NodeInfo>,
+    peers: Vec<SocketAddrV4>,
+    token: Option<Vec<u8>>,
+}
+
+impl Response {
+    fn parse(values: &HashMap<Vec<u8>, Value>) -> Result<Self> {
+        let id = Decoder::extract_bytes("id", values)?;
+        let nodes = match Decoder::extract_bytes("nodes", values) {
+            Ok(compact) => NodeInfo::from_compact(&compact)?,
+            Err(_) => vec![],
+        };
+        let peers = Decoder::extract_list("values", values)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| match value {
+                Value::Bytes(compact) => compact_peers(compact).ok(),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        Ok(Response {
+            id: NodeId(
+                id.try_into()
+                    .map_err(|_| eyre!("node id is not 20 bytes"))?,
+            ),
+            nodes,
+            peers,
+            token: Decoder::extract_bytes("token", values).ok(),
+        })
+    }
+}
+
+/// Result of an iterative lookup
+#[derive(Debug, Default)]
+struct Lookup {
+    /// The closest nodes that answered, with the token they handed out
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: HashSet<SocketAddrV4>,
+}
+
+/// Peers announced to us for one torrent, with the time of their last announcement
+#[derive(Debug)]
+struct TorrentPeers {
+    announced: Instant,
+    peers: Vec<(SocketAddrV4, Instant)>,
+}
+
+/// Peers announced to us, by info hash.
+/// Announcements run while the DHT state is locked, so none of them walks all torrents:
+/// expired peers of a torrent are dropped when it is announced to again, and torrents
+/// are kept in order of their last announcement to expire or evict the stalest first.
+#[derive(Debug, Default)]
+struct PeerStore {
+    torrents: HashMap<[u8; 20], TorrentPeers>,
+    by_announcement: BTreeSet<(Instant, [u8; 20])>,
+}
+
+impl PeerStore {
+    fn announce(&mut self, info_hash: [u8; 20], peer: SocketAddrV4, now: Instant) {
+        self.expire(now);
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            // Make room by dropping the torrent announced to least recently
+            if let Some((_, stalest)) = self.by_announcement.pop_first() {
+                self.torrents.remove(&stalest);
+            }
+        }
+
+        let torrent = self.torrents.entry(info_hash).or_insert(TorrentPeers {
+            announced: now,
+            peers: vec![],
+        });
+        self.by_announcement.remove(&(torrent.announced, info_hash));
+        torrent.announced = now;
+        self.by_announcement.insert((now, info_hash));
+
+        let peers = &mut torrent.peers;
+        peers.retain(|&(_, announced)| now.duration_since(announced) < PEER_LIFETIME);
+        match peers.iter_mut().find(|(known, _)| *known == peer) {
+            Some((_, announced)) => *announced = now,
+            None => {
+                if peers.len() >= MAX_PEERS_PER_TORRENT {
+                    peers.remove(0);
+                }
+                peers.push((peer, now));
+            }
+        }
+    }
+
+    fn get(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddrV4> {
+        self.torrents
+            .get(info_hash)
+            .into_iter()
+            .flat_map(|torrent| &torrent.peers)
+            .filter(|&&(_, announced)| now.duration_since(announced) < PEER_LIFETIME)
+            .map(|&(peer, _)| peer)
+            .collect()
+    }
+
+    /// Drop the torrents nobody announced to within the lifetime of a peer
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(announced, info_hash)) = self.by_announcement.first() {
+            if now.duration_since(announced) < PEER_LIFETIME {
+                break;
+            }
+            self.by_announcement.pop_first();
+            self.torrents.remove(&info_hash);
+        }
+    }
+}
+
+struct DhtState {
+    routing: RoutingTable,
+    peers: PeerStore,
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    secret_created: Instant,
+}
+
+impl DhtState {
+    fn rotate_secret(&mut self, now: Instant) {
+        if now.duration_since(self.secret_created) >= SECRET_LIFETIME {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.secret_created = now;
+        }
+    }
+
+    fn token(secret: &[u8; 16], ip: &Ipv4Addr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.octets());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn token_for(&self, ip: &Ipv4Addr) -> Vec<u8> {
+        DhtState::token(&self.secret, ip)
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: &Ipv4Addr) -> bool {
+        token == DhtState::token(&self.secret, ip)
+            || token == DhtState::token(&self.previous_secret, ip)
+    }
+}
+
+type PendingQueries = HashMap<u16, (SocketAddrV4, oneshot::Sender<Result<Response>>)>;
+
+/// A mainline DHT node (BEP 5), finds peers for an info hash without a tracker
+/// https://www.bittorrent.org/beps/bep_0005.html
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Mutex<DhtState>,
+    pending: Mutex<PendingQueries>,
+    next_tid: AtomicU16,
+}
+
+impl Dht {
+    /// Bind a node with a random id and start answering queries
+    pub async fn bind(addr: SocketAddrV4) -> Result<Arc<Self>> {
+        Dht::bind_with_id(addr, NodeId::random()).await
+    }
+
+    pub async fn bind_with_id(addr: SocketAddrV4, id: NodeId) -> Result<Arc<Self>> {
+        let socket = Arc::new(UdpSocket::bind(addr).await.context("bind DHT socket")?);
+        let now = Instant::now();
+        let dht = Arc::new(Dht {
+            id,
+            socket: socket.clone(),
+            state: Mutex::new(DhtState {
+                routing: RoutingTable::new(id),
+                peers: PeerStore::default(),
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_created: now,
+            }),
+            pending: Mutex::new(HashMap::new()),
+            next_tid: AtomicU16::new(rand::random()),
+        });
+
+        tokio::spawn(Dht::receive_loop(socket, Arc::downgrade(&dht)));
+        Ok(dht)
+    }
+
+    /// Bind a node restoring the id and routing table saved at `path`, if any.
+    /// A state file that cannot be read back only costs a fresh start.
+    pub async fn bind_with_state<T>(addr: SocketAddrV4, path: T) -> Result<Arc<Self>>
+    where
+        T: AsRef<Path>,
+    {
+        let Ok(saved) = std::fs::read(path.as_ref()) else {
+            return Dht::bind(addr).await;
+        };
+        let (id, nodes) = match Dht::parse_state(&saved) {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::warn!("Ignoring DHT state {}: {:#}", path.as_ref().display(), e);
+                return Dht::bind(addr).await;
+            }
+        };
+
+        let dht = Dht::bind_with_id(addr, id).await?;
+        {
+            let mut state = dht.state.lock().unwrap();
+            let now = Instant::now();
+            for node in nodes {
+                state.routing.insert(node, now);
+            }
+        }
+        Ok(dht)
+    }
+
+    fn parse_state(saved: &[u8]) -> Result<(NodeId, Vec<NodeInfo>)> {
+        let Value::Dict(saved) = serde_bencode::from_bytes(saved)? else {
+            return Err(eyre!("DHT state is not a dict"));
+        };
+        let id = Decoder::extract_bytes("id", &saved)?;
+        let id = NodeId(
+            id.try_into()
+                .map_err(|_| eyre!("node id is not 20 bytes"))?,
+        );
+        let nodes = NodeInfo::from_compact(&Decoder::extract_bytes("nodes", &saved)?)?;
+        Ok((id, nodes))
+    }
+
+    /// Save the node id and routing table so the next run does not start from scratch
+    pub fn save<T>(&self, path: T) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        let nodes: Vec<NodeInfo> = self.state.lock().unwrap().routing.nodes().collect();
+        let saved = dict([
+            ("id", Value::Bytes(self.id.0.to_vec())),
+            ("nodes", Value::Bytes(NodeInfo::to_compact(&nodes))),
+        ]);
+        std::fs::write(path, serde_bencode::to_bytes(&saved)?).context("save DHT state")
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        match self.socket.local_addr()? {
+            SocketAddr::V4(addr) => Ok(addr),
+            SocketAddr::V6(addr) => Err(eyre!("DHT bound to IPv6 address {addr}")),
+        }
+    }
+
+    /// Number of nodes in the routing table
+    pub fn routing_table_len(&self) -> usize {
+        self.state.lock().unwrap().routing.len()
+    }
+
+    /// Join the DHT through `nodes` given as `host:port`, filling the routing table
+    /// with the nodes closest to our own id
+    pub async fn bootstrap(&self, nodes: &[&str]) -> Result<()> {
+        let mut addrs = vec![];
+        for node in nodes {
+            match tokio::net::lookup_host(node).await {
+                Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => tracing::warn!("Cannot resolve DHT node {}: {}", node, e),
+            }
+        }
+
+        let pings = join_all(addrs.iter().map(|&addr| self.ping(addr))).await;
+        if pings.iter().all(Result::is_err) && self.routing_table_len() == 0 {
+            return Err(eyre!("no DHT bootstrap node answered"));
+        }
+
+        self.lookup(self.id, false).await;
+        tracing::info!("DHT bootstrapped with {} nodes", self.routing_table_len());
+        Ok(())
+    }
+
+    /// Find peers of a torrent
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(NodeId(info_hash), true).await;
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Tell the nodes closest to the info hash that we have the torrent on `port`,
+    /// returns the peers found on the way
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(NodeId(info_hash), true).await;
+
+        let announces = lookup.closest.iter().filter_map(|(node, token)| {
+            let args = dict([
+                ("info_hash", Value::Bytes(info_hash.to_vec())),
+                ("port", Value::Int(port as i64)),
+                ("token", Value::Bytes(token.clone()?)),
+            ]);
+            Some(self.query(node.addr, "announce_peer", into_dict(args)))
+        });
+        let announced = join_all(announces)
+            .await
+            .iter()
+            .filter(|r| r.is_ok())
+            .count();
+        tracing::debug!("Announced to {} DHT nodes", announced);
+
+        lookup.peers.into_iter().collect()
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId> {
+        let response = self.query(addr, "ping", HashMap::new()).await?;
+        Ok(response.id)
+    }
+
+    /// Iterative lookup of the `K` nodes closest to `target`, asking `ALPHA` nodes at a
+    /// time until the closest nodes known have all been queried
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = self.state.lock().unwrap().routing.closest(&target, K);
+        let mut queried: HashSet<SocketAddrV4> = HashSet::new();
+        let mut lookup = Lookup::default();
+
+        loop {
+            candidates.sort_by_key(|node| node.id.distance(&target));
+            candidates.dedup_by_key(|node| node.addr);
+            let batch: Vec<NodeInfo> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|node| {
+                queried.insert(node.addr);
+                let (method, key) = if get_peers {
+                    ("get_peers", "info_hash")
+                } else {
+                    ("find_node", "target")
+                };
+                let args = dict([(key, Value::Bytes(target.0.to_vec()))]);
+                self.query(node.addr, method, into_dict(args))
+            });
+
+            for (node, response) in batch.iter().zip(join_all(queries).await) {
+                let Ok(response) = response else {
+                    candidates.retain(|candidate| candidate.addr != node.addr);
+                    continue;
+                };
+                lookup.closest.push((*node, response.token));
+                lookup.peers.extend(response.peers);
+                candidates.extend(response.nodes.into_iter().filter(|n| n.id != self.id));
+            }
+        }
+
+        lookup
+            .closest
+            .sort_by_key(|(node, _)| node.id.distance(&target));
+        lookup.closest.truncate(K);
+        lookup
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: HashMap<Vec<u8>, Value>,
+    ) -> Result<Response> {
+        let tid = self.next_tid.fetch_add(1, Ordering::Relaxed);
+        args.insert(b"id".to_vec(), Value::Bytes(self.id.0.to_vec()));
+        let packet = Krpc::Query {
+            tid: tid.to_be_bytes().to_vec(),
+            method: method.to_string(),
+            args,
+        }
+        .to_bytes()?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(tid, (addr, sender));
+        self.socket.send_to(&packet, addr).await?;
+
+        let response = timeout(QUERY_TIMEOUT, receiver).await;
+        self.pending.lock().unwrap().remove(&tid);
+        let response = response
+            .context(format!("{method} to {addr} timed out"))?
+            .context("DHT node dropped")??;
+
+        self.state.lock().unwrap().routing.insert(
+            NodeInfo {
+                id: response.id,
+                addr,
+            },
+            Instant::now(),
+        );
+        Ok(response)
+    }
+
+    async fn receive_loop(socket: Arc<UdpSocket>, dht: Weak<Dht>) {
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+                continue;
+            };
+            let Some(dht) = dht.upgrade() else {
+                return;
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+
+            match Krpc::from_bytes(&buffer[..length]) {
+                Ok(message) => {
+                    if let Err(e) = dht.handle(message, from).await {
+                        tracing::debug!("DHT packet from {} failed: {:#}", from, e);
+                    }
+                }
+                Err(e) => tracing::debug!("Invalid DHT packet from {}: {:#}", from, e),
+            }
+        }
+    }
+
+    async fn handle(&self, message: Krpc, from: SocketAddrV4) -> Result<()> {
+        match message {
+            Krpc::Query { tid, method, args } => {
+                let reply = match self.answer(&method, &args, from) {
+                    Ok(values) => Krpc::Response { tid, values },
+                    Err((code, message)) => Krpc::Error { tid, code, message },
+                };
+                self.socket.send_to(&reply.to_bytes()?, from).await?;
+            }
+            Krpc::Response { tid, values } => {
+                if let Some(sender) = self.take_pending(&tid, from) {
+                    let _ = sender.send(Response::parse(&values));
+                }
+            }
+            Krpc::Error { tid, code, message } => {
+                if let Some(sender) = self.take_pending(&tid, from) {
+                    let _ = sender.send(Err(eyre!("DHT error {code}: {message}")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The waiting query of a response, only if it came from the node we asked
+    fn take_pending(
+        &self,
+        tid: &[u8],
+        from: SocketAddrV4,
+    ) -> Option<oneshot::Sender<Result<Response>>> {
+        let tid = u16::from_be_bytes(tid.try_into().ok()?);
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(&tid) {
+            Some((addr, _)) if *addr == from => pending.remove(&tid).map(|(_, sender)| sender),
+            _ => None,
+        }
+    }
+
+    fn answer(
+        &self,
+        method: &str,
+        args: &HashMap<Vec<u8>, Value>,
+        from: SocketAddrV4,
+    ) -> std::result::Result<HashMap<Vec<u8>, Value>, (i64, String)> {
+        let protocol_error = |e: Error| (ERROR_PROTOCOL, e.to_string());
+        let hash_arg = |key: &str| -> std::result::Result<[u8; 20], (i64, String)> {
+            Decoder::extract_bytes(key, args)
+                .map_err(protocol_error)?
+                .try_into()
+                .map_err(|_| (ERROR_PROTOCOL, format!("{key} is not 20 bytes")))
+        };
+
+        let id = NodeId(hash_arg("id")?);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.rotate_secret(now);
+        state.routing.insert(NodeInfo { id, addr: from }, now);
+
+        let mut values = HashMap::from([(b"id".to_vec(), Value::Bytes(self.id.0.to_vec()))]);
+        match method {
+            "ping" => {}
+            "find_node" => {
+                let target = NodeId(hash_arg("target")?);
+                let nodes = state.routing.closest(&target, K);
+                values.insert(
+                    b"nodes".to_vec(),
+                    Value::Bytes(NodeInfo::to_compact(&nodes)),
+                );
+            }
+            "get_peers" => {
+                let info_hash = hash_arg("info_hash")?;
+                values.insert(b"token".to_vec(), Value::Bytes(state.token_for(from.ip())));
+                let peers = state.peers.get(&info_hash, now);
+                match peers.as_slice() {
+                    [] => {
+                        let nodes = state.routing.closest(&NodeId(info_hash), K);
+                        values.insert(
+                            b"nodes".to_vec(),
+                            Value::Bytes(NodeInfo::to_compact(&nodes)),
+                        );
+                    }
+                    peers => {
+                        let peers = peers
+                            .iter()
+                            .map(|&peer| {
+                                Value::Bytes(Peers(vec![SocketAddr::V4(peer)]).to_compact())
+                            })
+                            .collect();
+                        values.insert(b"values".to_vec(), Value::List(peers));
+                    }
+                }
+            }
+            "announce_peer" => {
+                let info_hash = hash_arg("info_hash")?;
+                let token = Decoder::extract_bytes("token", args).map_err(protocol_error)?;
+                if !state.is_valid_token(&token, from.ip()) {
+                    return Err((ERROR_PROTOCOL, "bad token".to_string()));
+                }
+                let implied_port = Decoder::extract_int("implied_port", args).unwrap_or(0) != 0;
+                let port = if implied_port {
+                    from.port()
+                } else {
+                    let port = Decoder::extract_int("port", args).map_err(protocol_error)?;
+                    u16::try_from(port).map_err(|_| (ERROR_PROTOCOL, "invalid port".to_string()))?
+                };
+
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                state.peers.announce(info_hash, peer, now);
+            }
+            method => return Err((ERROR_METHOD_UNKNOWN, format!("unknown method {method}"))),
+        }
+
+        Ok(values)
+    }
+}
+
+/// Compact IPv4 peers, the DHT of BEP 5 only speaks IPv4
+fn compact_peers(compact: &[u8]) -> Result<Vec<SocketAddrV4>> {
+    Ok(Peers::from_compact(compact)?
+        .0
+        .into_iter()
+        .filter_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first_byte: u8, port: u16) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = first_byte;
+        NodeInfo {
+            id: NodeId(id),
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        }
+    }
+
+    #[test]
+    fn compact_node_info_round_trips() {
+        let nodes = vec![node(1, 6881), node(0xff, 1)];
+        let compact = NodeInfo::to_compact(&nodes);
+        assert_eq!(compact.len(), 52);
+        assert_eq!(NodeInfo::from_compact(&compact).unwrap(), nodes);
+        assert!(NodeInfo::from_compact(&compact[1..]).is_err());
+    }
+
+    #[test]
+    fn routing_table_keeps_k_nodes_per_bucket() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        let now = Instant::now();
+
+        // All of these share no prefix bit with our id and land in bucket 0
+        for i in 0..K as u8 + 2 {
+            table.insert(node(0x80 | i, 1000 + i as u16), now);
+        }
+        assert_eq!(table.len(), K);
+
+        // A stale node makes room
+        let later = now + STALE_AFTER;
+        assert!(table.insert(node(0xf0, 2000), later));
+        assert_eq!(table.len(), K);
+
+        // Closest nodes come first by XOR distance
+        table.insert(node(0x01, 3000), later);
+        table.insert(node(0x02, 3001), later);
+        let closest = table.closest(&NodeId([0; 20]), 3);
+        assert_eq!(closest[0], node(0x01, 3000));
+        assert_eq!(closest[1], node(0x02, 3001));
+        assert_eq!(closest[2].id.0[0] & 0x80, 0x80);
+
+        // Our own id is never stored
+        assert!(!table.insert(node(0, 1), later));
+    }
+
+    #[test]
+    fn tokens_survive_one_secret_rotation() {
+        let now = Instant::now();
+        let mut state = DhtState {
+            routing: RoutingTable::new(NodeId::random()),
+            peers: PeerStore::default(),
+            secret: rand::random(),
+            previous_secret: rand::random(),
+            secret_created: now,
+        };
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let token = state.token_for(&ip);
+        assert!(state.is_valid_token(&token, &ip));
+        assert!(!state.is_valid_token(&token, &Ipv4Addr::new(10, 0, 0, 2)));
+
+        state.rotate_secret(now + SECRET_LIFETIME);
+        assert!(state.is_valid_token(&token, &ip));
+        state.rotate_secret(now + SECRET_LIFETIME * 2);
+        assert!(!state.is_valid_token(&token, &ip));
+    }
+
+    async fn local_node() -> Arc<Dht> {
+        Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn announced_peers_expire_and_torrents_are_bounded() {
+        let now = Instant::now();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let mut store = PeerStore::default();
+
+        store.announce([0; 20], peer(1), now);
+        store.announce([0; 20], peer(2), now + PEER_LIFETIME / 2);
+        assert_eq!(store.get(&[0; 20], now + PEER_LIFETIME), vec![peer(2)]);
+        // Announcing again keeps a peer alive
+        store.announce([0; 20], peer(2), now + PEER_LIFETIME);
+        assert_eq!(
+            store.get(&[0; 20], now + PEER_LIFETIME * 3 / 2),
+            vec![peer(2)]
+        );
+
+        let later = now + PEER_LIFETIME * 3 / 2;
+        for i in 0..MAX_TORRENTS as u32 + 10 {
+            let mut info_hash = [0xff; 20];
+            info_hash[..4].copy_from_slice(&i.to_be_bytes());
+            store.announce(info_hash, peer(3), later + Duration::from_millis(i as u64));
+        }
+        assert_eq!(store.torrents.len(), MAX_TORRENTS);
+        assert_eq!(store.by_announcement.len(), MAX_TORRENTS);
+        // The least recently announced torrents made room
+        assert!(store.get(&[0; 20], later).is_empty());
+
+        store.expire(later + PEER_LIFETIME * 2);
+        assert!(store.torrents.is_empty());
+        assert!(store.by_announcement.is_empty());
+    }
+
+    #[tokio::test]
+    async fn swarm_of_nodes_finds_announced_peers() {
+        let bootstrap = local_node().await;
+        let bootstrap_addr = bootstrap.local_addr().unwrap().to_string();
+
+        let mut nodes = vec![];
+        for _ in 0..12 {
+            let node = local_node().await;
+            node.bootstrap(&[&bootstrap_addr]).await.unwrap();
+            nodes.push(node);
+        }
+        assert!(nodes.iter().all(|node| node.routing_table_len() > 1));
+
+        let info_hash = [0x42; 20];
+        let seeder = &nodes[3];
+        let found = seeder.announce(info_hash, 51413).await;
+        assert!(found.is_empty());
+
+        let peers = nodes[11].get_peers(info_hash).await;
+        assert_eq!(peers, vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 51413)]);
+
+        // Unknown methods and bad tokens are refused
+        let node = &nodes[0];
+        let target = nodes[1].local_addr().unwrap();
+        assert!(node.query(target, "vote", HashMap::new()).await.is_err());
+        let args = dict([
+            ("info_hash", Value::Bytes(info_hash.to_vec())),
+            ("port", Value::Int(1)),
+            ("token", Value::Bytes(b"forged".to_vec())),
+        ]);
+        assert!(node
+            .query(target, "announce_peer", into_dict(args))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn routing_table_is_restored_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht.state");
+
+        let bootstrap = local_node().await;
+        let node = local_node().await;
+        node.bootstrap(&[&bootstrap.local_addr().unwrap().to_string()])
+            .await
+            .unwrap();
+        node.save(&path).unwrap();
+
+        let restored = Dht::bind_with_state(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), &path)
+            .await
+            .unwrap();
+        assert_eq!(restored.id(), node.id());
+        assert_eq!(restored.routing_table_len(), node.routing_table_len());
+        assert_eq!(
+            restored
+                .ping(bootstrap.local_addr().unwrap())
+                .await
+                .unwrap(),
+            bootstrap.id()
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_starts_a_fresh_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht.state");
+        std::fs::write(&path, b"d2:id20:truncat").unwrap();
+
+        let node = Dht::bind_with_state(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), &path)
+            .await
+            .unwrap();
+        assert_eq!(node.routing_table_len(), 0);
+    }
+}
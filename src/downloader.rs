@@ -1,42 +1,73 @@
-use eyre::Result;
+use std::{collections::HashMap, future::pending, io, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use sha1::{Digest, Sha1};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::sync::watch;
 
 use crate::{
-    peer_message::{Message, BLOCK_SIZE, MESSAGE},
+    codec::PeerStream,
+    error::{Error, Result},
+    peer_message::{Message, BLOCK_SIZE},
+    storage::{blocking, Storage},
     TorrentResponse,
 };
 
+/// Number of block requests kept in flight per peer unless configured otherwise
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+
+/// Lets the caller of a piece download follow the peer while blocks are coming in
+pub trait PieceObserver {
+    /// The peer announced a new piece with a HAVE message
+    fn on_have(&mut self, _piece: usize) {}
+
+    /// The peer delivered `block` at `begin` of `piece`
+    fn on_block(&mut self, _piece: usize, _begin: u32, _block: &Bytes) {}
+
+    /// The peer sent an extension message (BEP 10)
+    fn on_extended(&mut self, _id: u8, _payload: Bytes) {}
+
+    /// The peer choked us, dropping the outstanding requests
+    fn on_choke(&mut self) {}
+
+    /// Whether the piece is no longer needed, e.g. another peer delivered it first
+    fn is_cancelled(&self, _piece: usize) -> bool {
+        false
+    }
+
+    /// The block at `begin` of `piece` if it arrived already, e.g. from another peer.
+    /// It is then taken from there instead of being requested, or cancelled if it was.
+    fn delivered(&self, _piece: usize, _begin: u32) -> Option<Bytes> {
+        None
+    }
+
+    /// Changes whenever a block is delivered by any peer, see [`PieceObserver::delivered`]
+    fn deliveries(&self) -> Option<watch::Receiver<()>> {
+        None
+    }
+}
+
+impl PieceObserver for () {}
+
 pub struct Downloader;
 
 impl Downloader {
     pub async fn download_a_piece(
         output_path: &str,
-        peer: &mut TcpStream,
+        peer: &mut PeerStream,
         torrent: &TorrentResponse,
         piece_index: &i32,
     ) -> Result<()> {
-        // Receive "Bitfield" peer message and get the number of pieces
-        let pieces = Downloader::get_pieces(peer).await?;
+        // Get the pieces of the peer on the way to being unchoked
+        let pieces = Downloader::interested(peer).await?;
 
         if pieces.contains(piece_index) {
-            // Send "Interested" peer message
-            Downloader::send(peer, Message::new(MESSAGE::INTERESTED, vec![])).await?;
-
-            // receive "Unchoke" peer message
-            let unchoke = Downloader::receive(peer).await?;
-            assert_eq!(unchoke.id, MESSAGE::UNCHOKE);
-
             // Download the piece
-            let downloaded_piece = Downloader::download(peer, torrent, piece_index).await?;
+            let downloaded_piece =
+                Downloader::download(peer, torrent, piece_index, DEFAULT_PIPELINE_DEPTH).await?;
 
-            // Write to file async
-            let mut file = File::create(output_path).await.unwrap();
-            file.write_all(downloaded_piece.as_slice()).await.unwrap();
+            // The piece alone is the output, it is not placed within the torrent's files
+            tokio::fs::write(output_path, &downloaded_piece).await?;
 
             println!("Piece {piece_index} downloaded to {output_path}.");
         }
@@ -44,50 +75,73 @@ impl Downloader {
         Ok(())
     }
 
+    /// Download every piece the peer has into `storage`, one piece at a time
     pub async fn download_complete_pieces(
-        output_path: &str,
-        peer: &mut TcpStream,
+        storage: &Arc<dyn Storage>,
+        peer: &mut PeerStream,
         torrent: &TorrentResponse,
     ) -> Result<()> {
-        // Receive "Bitfield" peer message and get the number of pieces
-        let pieces = Downloader::get_pieces(peer).await?;
+        // Get the pieces of the peer on the way to being unchoked
+        let pieces = Downloader::interested(peer).await?;
         tracing::info!("Total pieces: {:#?}", &pieces);
 
-        // Send "Interested" peer message
-        Downloader::send(peer, Message::new(MESSAGE::INTERESTED, vec![])).await?;
-
-        // receive "Unchoke" peer message
-        let unchoke = Downloader::receive(peer).await?;
-        assert_eq!(unchoke.id, MESSAGE::UNCHOKE);
-
-        let mut complete_pieces = vec![];
+        blocking(storage, |storage| storage.preallocate())
+            .await
+            .map_err(Error::storage)?;
 
         // Download the pieces
         for piece_index in 0..pieces.len() {
             tracing::info!("Downloading piece {}/{}", piece_index + 1, pieces.len());
             let piece_id = piece_index as i32;
-            let downloaded_piece = Downloader::download(peer, torrent, &piece_id).await?;
-            complete_pieces.push(downloaded_piece);
+            let downloaded_piece =
+                Downloader::download(peer, torrent, &piece_id, DEFAULT_PIPELINE_DEPTH).await?;
+
+            // Only the piece in flight is held in memory
+            blocking(storage, move |storage| {
+                storage.write_block(piece_index, 0, &downloaded_piece)
+            })
+            .await
+            .map_err(Error::storage)?;
             tracing::info!("Piece {}/{} downloaded", piece_id + 1, pieces.len());
         }
 
-        // Write to file async
-        tracing::info!("Writing downloaded bytes to file at {}", output_path);
-        let mut file = File::create(output_path).await.unwrap();
-        let bytes = complete_pieces.concat();
-        file.write_all(bytes.as_slice()).await.unwrap();
+        blocking(storage, |storage| storage.flush())
+            .await
+            .map_err(Error::storage)?;
 
         Ok(())
     }
 
-    async fn download(
-        peer: &mut TcpStream,
+    pub(crate) async fn download(
+        peer: &mut PeerStream,
         torrent: &TorrentResponse,
         piece_id: &i32,
+        depth: usize,
     ) -> Result<Vec<u8>> {
-        let loaded_piece = Downloader::load_piece(peer, piece_id, torrent).await?;
+        let loaded_piece = Downloader::download_observed(peer, torrent, piece_id, depth, &mut ())
+            .await?
+            .ok_or_else(|| Error::protocol(format!("choked while downloading piece {piece_id}")))?;
 
-        let hash_from_file = Downloader::get_piece_hash(*piece_id, torrent);
+        Ok(loaded_piece)
+    }
+
+    /// Download and verify a piece, returns `None` if the observer cancelled it or the
+    /// peer choked us.
+    /// Data that does not match the piece hash fails with [`Error::HashMismatch`].
+    pub(crate) async fn download_observed(
+        peer: &mut PeerStream,
+        torrent: &TorrentResponse,
+        piece_id: &i32,
+        depth: usize,
+        observer: &mut impl PieceObserver,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(loaded_piece) =
+            Downloader::load_piece_observed(peer, piece_id, torrent, depth, observer).await?
+        else {
+            return Ok(None);
+        };
+
+        let hash_from_file = Downloader::get_piece_hash(*piece_id, torrent)?;
 
         let mut hasher = Sha1::new();
 
@@ -95,151 +149,335 @@ impl Downloader {
 
         let real_hash: [u8; 20] = hasher.finalize().into();
 
-        assert_eq!(hash_from_file, real_hash);
+        if hash_from_file != real_hash {
+            return Err(Error::HashMismatch {
+                piece: *piece_id as usize,
+            });
+        }
 
-        Ok(loaded_piece)
+        Ok(Some(loaded_piece))
     }
 
-    async fn get_pieces(peer: &mut TcpStream) -> Result<Vec<i32>> {
-        let bitfield = Downloader::receive(peer).await?;
+    /// Send INTERESTED and wait for UNCHOKE, returns the pieces the peer announced meanwhile.
+    /// The BITFIELD may follow the extended handshake, and a peer without pieces may not send one.
+    async fn interested(peer: &mut PeerStream) -> Result<Vec<i32>> {
+        Downloader::send(peer, Message::Interested).await?;
 
-        assert_eq!(bitfield.id, MESSAGE::BITFIELD);
-
-        let bitfield = bitfield.payload;
-        let bitset: Vec<char> = bitfield
-            .into_iter()
-            .flat_map(|byte| format!("{:b}", byte).chars().collect::<Vec<char>>())
-            .collect();
+        let mut pieces = vec![];
+        loop {
+            match Downloader::receive(peer).await? {
+                Message::Unchoke => break,
+                Message::Bitfield(bitfield) => {
+                    pieces.extend(Downloader::bitfield_pieces(&bitfield))
+                }
+                Message::Have { index } => pieces.push(index as i32),
+                message => {
+                    tracing::debug!("Ignoring {:?} while waiting for UNCHOKE", message.id())
+                }
+            }
+        }
+        pieces.sort_unstable();
+        pieces.dedup();
+        Ok(pieces)
+    }
 
-        let pieces = bitset
-            .into_iter()
+    /// The pieces set in a BITFIELD
+    pub(crate) fn bitfield_pieces(bitfield: &[u8]) -> Vec<i32> {
+        // The high bit of the first byte corresponds to piece index 0
+        bitfield
+            .iter()
+            .flat_map(|byte| (0..8).map(move |bit| byte & (0x80 >> bit) != 0))
             .enumerate()
-            .filter(|(_, b)| b.eq(&'1'))
+            .filter(|(_, has)| *has)
             .map(|(i, _)| i as i32)
-            .collect::<Vec<i32>>();
-
-        Ok(pieces)
+            .collect()
     }
 
-    pub fn get_piece_hash(piece: i32, torrent: &TorrentResponse) -> [u8; 20] {
-        let hashes: Vec<&[u8]> = torrent.info.pieces.chunks(20).collect();
-        hashes[piece as usize].try_into().unwrap()
+    pub fn get_piece_hash(piece: i32, torrent: &TorrentResponse) -> Result<[u8; 20]> {
+        usize::try_from(piece)
+            .ok()
+            .and_then(|piece| torrent.info.pieces.chunks_exact(20).nth(piece))
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| Error::metainfo(format!("torrent has no piece {piece}")))
     }
 
+    /// Download a piece keeping up to `depth` block requests outstanding at once.
+    /// Blocks are placed by their `begin` offset, so replies may arrive in any order.
     pub async fn load_piece(
-        peer: &mut TcpStream,
+        peer: &mut PeerStream,
         index: &i32,
         torrent: &TorrentResponse,
+        depth: usize,
     ) -> Result<Vec<u8>> {
-        // Downloader::send(peer, Message::new(MESSAGE::INTERESTED, vec![])).await?;
-
-        // let unchoke = Downloader::receive(peer).await?;
-        // assert_eq!(unchoke.id, MESSAGE::UNCHOKE);
+        Downloader::load_piece_observed(peer, index, torrent, depth, &mut ())
+            .await?
+            .ok_or_else(|| Error::protocol(format!("choked while downloading piece {index}")))
+    }
 
+    /// Same as `load_piece`, reporting HAVE messages to the observer and sending CANCEL for
+    /// the outstanding requests when the observer says the piece is no longer needed.
+    /// Blocks the observer knows from other peers are not requested, and a request is
+    /// cancelled as soon as another peer delivers its block (endgame).
+    /// Returns `None` for a cancelled piece, and when the peer chokes us and drops the
+    /// requests, after telling the observer.
+    pub async fn load_piece_observed(
+        peer: &mut PeerStream,
+        index: &i32,
+        torrent: &TorrentResponse,
+        depth: usize,
+        observer: &mut impl PieceObserver,
+    ) -> Result<Option<Vec<u8>>> {
         // Get size of the piece
-        let file_size = torrent.info.length as i32;
-        let piece_len: i32 = torrent.info.piece_length as i32;
-        let piece_size = piece_len.min(file_size - piece_len * index);
+        Downloader::get_piece_hash(*index, torrent)?;
+        let piece_size = torrent.info.piece_size(*index as usize) as i32;
 
         tracing::info!("Piece size: {piece_size}");
 
         // Break the piece into blocks of 16 kiB (16 * 1024 bytes)
-        // and send a request message for each block
-        let mut piece: Vec<u8> = Vec::new();
+        // and keep the request queue filled up to the target depth
+        let mut piece = vec![0u8; piece_size as usize];
+        let mut outstanding: HashMap<i32, i32> = HashMap::new();
+        let mut next_offset = 0;
         let mut remain = piece_size;
-        let mut offset = 0;
+        let mut deliveries = observer.deliveries();
 
         loop {
-            if remain == 0 {
-                // all blocks have been loaded
-                break;
+            if observer.is_cancelled(*index as usize) {
+                // Another peer delivered the piece first (endgame)
+                for (begin, length) in outstanding {
+                    Downloader::cancel_block(peer, index, begin, length).await?;
+                }
+                return Ok(None);
             }
 
-            let block_size = BLOCK_SIZE.min(remain);
+            // Blocks other peers delivered meanwhile are no longer needed from this one
+            let delivered: Vec<(i32, Bytes)> = outstanding
+                .keys()
+                .filter_map(|&begin| {
+                    let block = observer.delivered(*index as usize, begin as u32)?;
+                    Some((begin, block))
+                })
+                .collect();
+            for (begin, block) in delivered {
+                let length = outstanding.remove(&begin).unwrap_or_default();
+                Downloader::cancel_block(peer, index, begin, length).await?;
+                if block.len() == length as usize {
+                    piece[begin as usize..begin as usize + block.len()].copy_from_slice(&block);
+                    remain -= length;
+                }
+            }
+
+            while outstanding.len() < depth.max(1) && next_offset < piece_size {
+                let block_size = BLOCK_SIZE.min(piece_size - next_offset);
+                match observer.delivered(*index as usize, next_offset as u32) {
+                    Some(block) if block.len() == block_size as usize => {
+                        piece[next_offset as usize..(next_offset + block_size) as usize]
+                            .copy_from_slice(&block);
+                        remain -= block_size;
+                    }
+                    _ => {
+                        Downloader::request_block(peer, index, next_offset, block_size).await?;
+                        outstanding.insert(next_offset, block_size);
+                    }
+                }
+                next_offset += block_size;
+            }
 
-            let response = Downloader::load_block(peer, index, offset, block_size).await?;
+            if remain == 0 {
+                break;
+            }
 
-            remain -= block_size;
-            offset += block_size;
+            let response = tokio::select! {
+                response = Downloader::receive(peer) => response?,
+                // Look again at what other peers delivered
+                _ = Downloader::changed(&mut deliveries) => continue,
+            };
+
+            let (block_index, begin, block) = match response {
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    observer.on_block(index as usize, begin, &block);
+                    (index as i32, begin as i32, block)
+                }
+                Message::Have { index } => {
+                    observer.on_have(index as usize);
+                    continue;
+                }
+                Message::Extended { id, payload } => {
+                    observer.on_extended(id, payload);
+                    continue;
+                }
+                Message::Choke => {
+                    // Requests are dropped by a choking peer, the piece is given up
+                    observer.on_choke();
+                    return Ok(None);
+                }
+                message => {
+                    tracing::debug!("Ignoring {:?} while waiting for blocks", message.id());
+                    continue;
+                }
+            };
+
+            if block_index != *index {
+                tracing::debug!("Ignoring block of piece {block_index}, expected {index}");
+                continue;
+            }
+            match outstanding.remove(&begin) {
+                Some(length) if length as usize == block.len() => {}
+                Some(length) => {
+                    return Err(Error::protocol(format!(
+                        "block at {begin} has {} bytes, requested {length}",
+                        block.len()
+                    )))
+                }
+                None => {
+                    tracing::debug!("Ignoring unrequested block at {begin}");
+                    continue;
+                }
+            }
 
-            println!("Remain {remain}");
+            piece[begin as usize..begin as usize + block.len()].copy_from_slice(&block);
+            remain -= block.len() as i32;
 
-            let block = &response.payload[8..];
+            tracing::debug!("Remain {remain}");
+        }
 
-            assert_eq!(response.prefix as usize - 9, block.len());
+        Ok(Some(piece))
+    }
 
-            piece.append(&mut block.to_vec());
+    /// Resolves when `deliveries` changes, never if there is nothing to watch
+    async fn changed(deliveries: &mut Option<watch::Receiver<()>>) {
+        let Some(receiver) = deliveries else {
+            return pending().await;
+        };
+        if receiver.changed().await.is_err() {
+            pending().await
         }
+    }
 
-        Ok(piece)
+    async fn cancel_block(
+        peer: &mut PeerStream,
+        index: &i32,
+        begin: i32,
+        length: i32,
+    ) -> Result<()> {
+        let cancel = Message::Cancel {
+            index: *index as u32,
+            begin: begin as u32,
+            length: length as u32,
+        };
+        Downloader::send(peer, cancel).await
     }
 
-    async fn load_block(
-        peer: &mut TcpStream,
+    async fn request_block(
+        peer: &mut PeerStream,
         index: &i32,
         begin: i32,
         length: i32,
-    ) -> Result<Message> {
+    ) -> Result<()> {
         // request: <len=0013><id=6><index><begin><length>
-        println!("Index: {index}");
-        println!("Begin {begin}");
-        println!("Length {length}");
-
-        let payload: Vec<u8> = [
-            index.to_be_bytes().as_slice(),
-            begin.to_be_bytes().as_slice(),
-            length.to_be_bytes().as_slice(),
-        ]
-        .concat();
-
-        let request = Message::new(MESSAGE::REQUEST, payload);
+        let request = Message::Request {
+            index: *index as u32,
+            begin: begin as u32,
+            length: length as u32,
+        };
 
-        println!("Request {:?}", request);
+        tracing::debug!("Request {:?}", request);
 
-        Downloader::send(peer, request).await?;
-
-        let block = Downloader::receive(peer).await?;
-
-        assert_eq!(block.id, MESSAGE::PIECE);
-
-        Ok(block)
+        Downloader::send(peer, request).await
     }
 
-    async fn send(peer: &mut TcpStream, message: Message) -> Result<()> {
-        tracing::info!("Sending peer message INTERESTED.");
-        peer.write_all(message.to_bytes().as_slice()).await?;
-        Ok(())
+    /// Send a message to the peer
+    pub(crate) async fn send(peer: &mut PeerStream, message: Message) -> Result<()> {
+        tracing::debug!("Sending peer message {:?}", message.id());
+        peer.send(message).await
     }
 
-    async fn receive(peer: &mut TcpStream) -> Result<Message> {
-        tracing::info!("Receiving peer message UNCHOKE");
-        let prefix = Downloader::read_prefix(peer).await?;
-        let id = Downloader::read_message_id(peer).await?;
-        let payload = Downloader::read_payload(peer, prefix).await?;
+    /// Receive the next message, keep-alives are skipped
+    pub(crate) async fn receive(peer: &mut PeerStream) -> Result<Message> {
+        loop {
+            let message = peer.next().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the connection")
+            })??;
 
-        Ok(Message {
-            prefix,
-            id,
-            payload,
-        })
-    }
+            if message == Message::KeepAlive {
+                tracing::debug!("Received keep-alive");
+                continue;
+            }
 
-    async fn read_prefix(session: &mut TcpStream) -> Result<i32> {
-        let mut buf = [0u8; 4];
-        session.read_exact(&mut buf).await?;
-        Ok(i32::from_be_bytes(buf))
+            tracing::debug!("Received peer message {:?}", message.id());
+            return Ok(message);
+        }
     }
+}
 
-    async fn read_message_id(session: &mut TcpStream) -> Result<MESSAGE> {
-        let mut buf = [0u8; 1];
-        session.read_exact(&mut buf).await?;
-        let id = MESSAGE::try_from(buf[0])?;
-        Ok(id)
-    }
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{codec::PeerCodec, Info};
+
+    #[tokio::test]
+    async fn pipelined_blocks_are_placed_by_offset() {
+        let data: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let torrent = TorrentResponse {
+            info: Info {
+                name: "sample.txt".to_string(),
+                length: data.len() as i64,
+                files: None,
+                piece_length: data.len() as i64,
+                pieces: Sha1::digest(&data).to_vec(),
+            },
+            announce_url: String::new(),
+            announce_list: vec![],
+            hash: String::new(),
+            raw_info: vec![],
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = data.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            // All three requests arrive before any block is sent, then answer them in reverse
+            let mut requests = vec![];
+            for _ in 0..3 {
+                let mut request = [0u8; 17];
+                socket.read_exact(&mut request).await.unwrap();
+                let Message::Request { begin, length, .. } = Message::from_bytes(&request).unwrap()
+                else {
+                    panic!("expected REQUEST");
+                };
+                requests.push((begin as usize, length as usize));
+            }
+
+            for (begin, length) in requests.into_iter().rev() {
+                let message = Message::Piece {
+                    index: 0,
+                    begin: begin as u32,
+                    block: Bytes::copy_from_slice(&served[begin..begin + length]),
+                };
+                socket.write_all(&message.to_bytes()).await.unwrap();
+                // Keep-alives in between blocks are skipped
+                socket
+                    .write_all(&Message::KeepAlive.to_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
 
-    async fn read_payload(session: &mut TcpStream, prefix: i32) -> Result<Vec<u8>> {
-        let mut buf = vec![0u8; prefix as usize - 1];
-        session.read_exact(&mut buf).await?;
-        Ok(buf)
+        let mut peer = PeerCodec::framed(TcpStream::connect(addr).await.unwrap());
+        let piece = Downloader::download(&mut peer, &torrent, &0, 3)
+            .await
+            .unwrap();
+        assert_eq!(piece, data);
     }
 }
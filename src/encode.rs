@@ -3,14 +3,78 @@ use std::{
     io::{Read, Write},
 };
 
-use eyre::Result;
+use eyre::{eyre, Result};
 use sha1::{Digest, Sha1};
 
-use crate::{Info, TorrentRequest};
+use crate::{decode::ByteEncoding, Info, TorrentRequest};
 
 pub struct Encoder;
 impl Encoder {
-    pub fn encode_file(file_path: &str, announce_url: &str, piece_length: i64) -> Result<()> {
+    /// Canonical bencode of `value`: dictionary keys sorted by their bytes, strings
+    /// turned back into bytes with `bytes`. The inverse of [`crate::decode::Decoder::to_json`].
+    pub fn encode_json(value: &serde_json::Value, bytes: ByteEncoding) -> Result<Vec<u8>> {
+        let mut encoded = vec![];
+        Encoder::write_json(value, bytes, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    fn write_json(value: &serde_json::Value, bytes: ByteEncoding, out: &mut Vec<u8>) -> Result<()> {
+        match value {
+            serde_json::Value::Number(number) => {
+                let int = number
+                    .as_i64()
+                    .ok_or_else(|| eyre!("{number} is not an integer bencode can hold"))?;
+                out.extend_from_slice(format!("i{int}e").as_bytes());
+            }
+            serde_json::Value::String(string) => {
+                Encoder::write_bytes(&bytes.parse(string)?, out);
+            }
+            serde_json::Value::Array(array) => {
+                out.push(b'l');
+                for item in array {
+                    Encoder::write_json(item, bytes, out)?;
+                }
+                out.push(b'e');
+            }
+            serde_json::Value::Object(object) => {
+                let mut entries = object
+                    .iter()
+                    .map(|(key, value)| Ok((bytes.parse(key)?, value)))
+                    .collect::<Result<Vec<_>>>()?;
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                    return Err(eyre!(
+                        "duplicate key {}",
+                        String::from_utf8_lossy(&pair[0].0)
+                    ));
+                }
+
+                out.push(b'd');
+                for (key, value) in entries {
+                    Encoder::write_bytes(&key, out);
+                    Encoder::write_json(value, bytes, out)?;
+                }
+                out.push(b'e');
+            }
+            serde_json::Value::Bool(_) | serde_json::Value::Null => {
+                return Err(eyre!("{value} has no bencode representation"))
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    /// Create a single-file torrent for `file_path`, written to `output_path`
+    pub fn encode_file(
+        file_path: &str,
+        announce_url: &str,
+        piece_length: i64,
+        output_path: &str,
+    ) -> Result<()> {
         // Read the file
         let mut file = File::open(file_path)?;
         let mut buffer = Vec::new();
@@ -28,6 +92,7 @@ impl Encoder {
         let info = Info {
             name: file_path.to_string(),
             length: buffer.len() as i64,
+            files: None,
             piece_length,
             pieces,
         };
@@ -39,10 +104,10 @@ impl Encoder {
         };
 
         // Serialize to bencode
-        let bencoded = serde_bencode::to_bytes(&torrent).unwrap();
+        let bencoded = serde_bencode::to_bytes(&torrent)?;
 
         // Write to file
-        let mut output_file = File::create("examples/example_file.torrent")?;
+        let mut output_file = File::create(output_path)?;
         output_file.write_all(&bencoded)?;
 
         println!("Torrent file created successfully!");
@@ -50,3 +115,39 @@ impl Encoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::decode::Decoder;
+
+    #[test]
+    fn encodes_with_sorted_keys() {
+        let value = json!({"zz": [1, -2], "a": {"hex:00ff": "x"}, "b": "hex:6869"});
+        assert_eq!(
+            Encoder::encode_json(&value, ByteEncoding::Hex).unwrap(),
+            b"d1:ad2:\x00\xff1:xe1:b2:hi2:zzli1ei-2eee"
+        );
+
+        assert!(Encoder::encode_json(&json!(1.5), ByteEncoding::Hex).is_err());
+        assert!(Encoder::encode_json(&json!([null]), ByteEncoding::Hex).is_err());
+        // Both keys are the byte string `a`
+        let duplicate = json!({"a": 1, "hex:61": 2});
+        assert!(Encoder::encode_json(&duplicate, ByteEncoding::Hex).is_err());
+    }
+
+    #[test]
+    fn decoded_json_encodes_back_to_the_same_bencode() {
+        let torrent = std::fs::read("sample.torrent").unwrap();
+        for encoding in [
+            ByteEncoding::Hex,
+            ByteEncoding::Base64,
+            ByteEncoding::Escaped,
+        ] {
+            let json = Decoder::to_json(&torrent, encoding).unwrap();
+            assert_eq!(Encoder::encode_json(&json, encoding).unwrap(), torrent);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::{io, net::SocketAddr};
+
+/// The cause of an [`Error`], kept as its source so that the whole chain can be reported
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What went wrong, by where it went wrong.
+/// Callers match on the variant, the details are in the source chain.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Data that is not valid bencode
+    #[error("invalid bencode")]
+    Bencode(#[source] BoxError),
+
+    /// A bencoded dictionary lacks a field, or it has the wrong type
+    #[error("missing or invalid field `{0}`")]
+    MissingField(String),
+
+    /// A torrent file or info dictionary that does not describe a torrent
+    #[error("invalid metainfo")]
+    Metainfo(#[source] BoxError),
+
+    /// A tracker could not be reached, answered with garbage or refused the request
+    #[error("tracker {url} failed")]
+    Tracker {
+        url: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// Connecting to a peer or exchanging handshakes with it failed
+    #[error("handshake with {peer} failed")]
+    Handshake {
+        peer: SocketAddr,
+        #[source]
+        source: BoxError,
+    },
+
+    /// A peer sent something the protocol does not allow
+    #[error("protocol violation")]
+    Protocol(#[source] BoxError),
+
+    /// Reading or writing the torrent's data failed
+    #[error("storage failed")]
+    Storage(#[source] BoxError),
+
+    /// A downloaded piece did not hash to the value in the torrent, the data is discarded
+    #[error("piece {piece} failed the hash check")]
+    HashMismatch { piece: usize },
+
+    #[error("I/O failed")]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    pub fn bencode(source: impl Into<BoxError>) -> Self {
+        Error::Bencode(source.into())
+    }
+
+    pub fn metainfo(source: impl Into<BoxError>) -> Self {
+        Error::Metainfo(source.into())
+    }
+
+    pub fn tracker(url: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Error::Tracker {
+            url: url.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn handshake(peer: SocketAddr, source: impl Into<BoxError>) -> Self {
+        Error::Handshake {
+            peer,
+            source: source.into(),
+        }
+    }
+
+    pub fn protocol(source: impl Into<BoxError>) -> Self {
+        Error::Protocol(source.into())
+    }
+
+    pub fn storage(source: impl Into<BoxError>) -> Self {
+        Error::Storage(source.into())
+    }
+
+    /// The error followed by its sources, for logging
+    pub fn chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            message.push_str(": ");
+            message.push_str(&error.to_string());
+            source = error.source();
+        }
+        message
+    }
+}
+
+impl From<crate::bencode::ParseError> for Error {
+    fn from(e: crate::bencode::ParseError) -> Self {
+        Error::bencode(e)
+    }
+}
+
+impl From<serde_bencode::Error> for Error {
+    fn from(e: serde_bencode::Error) -> Self {
+        Error::bencode(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Error::bencode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_source_chain() {
+        let error = Error::tracker("udp://t:1", "connection refused");
+        assert_eq!(error.to_string(), "tracker udp://t:1 failed");
+        assert_eq!(
+            error.chain(),
+            "tracker udp://t:1 failed: connection refused"
+        );
+
+        let report = eyre::Report::from(error);
+        assert_eq!(
+            format!("{report:#}"),
+            "tracker udp://t:1 failed: connection refused"
+        );
+        assert!(matches!(
+            report.downcast_ref::<Error>(),
+            Some(Error::Tracker { .. })
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use bytes::Bytes;
+use eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peer_message::Message;
+
+/// Extended message id of the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Client name and version sent as `v`
+pub const CLIENT_VERSION: &str = concat!("bittorrent-rust ", env!("CARGO_PKG_VERSION"));
+
+/// Number of outstanding requests we accept, sent as `reqq`
+pub const REQUEST_QUEUE: i64 = 250;
+
+/// The extended handshake
+/// https://www.bittorrent.org/beps/bep_0010.html#handshake-message
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names and the message ids the sender wants them to use, 0 disables one
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Our address as seen by the sender, 4 or 16 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// Number of outstanding requests the sender accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Size of the info dictionary, for ut_metadata (BEP 9)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        serde_bencode::from_bytes(bytes).context("parse extended handshake")
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("encode extended handshake")
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let ip: &[u8] = self.yourip.as_deref()?;
+        if let Ok(octets) = <[u8; 4]>::try_from(ip) {
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        } else if let Ok(octets) = <[u8; 16]>::try_from(ip) {
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_your_ip(&mut self, ip: IpAddr) {
+        let octets = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        self.yourip = Some(ByteBuf::from(octets));
+    }
+}
+
+/// An extended message after routing it by extension name
+#[derive(Debug, PartialEq)]
+pub enum Extended {
+    /// The peer sent its extended handshake, the registry already learned its ids
+    Handshake(ExtendedHandshake),
+    /// A message for one of the registered extensions
+    Message { name: &'static str, payload: Bytes },
+}
+
+/// The extensions a connection speaks (BEP 10).
+/// Extensions plug in by name and get a local message id, the peer's ids for them
+/// are learned from its extended handshake and used when sending.
+#[derive(Debug, Default)]
+pub struct ExtensionRegistry {
+    /// Local message id `i + 1` belongs to `local[i]`
+    local: Vec<&'static str>,
+    remote: HashMap<String, u8>,
+    peer: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: &'static str) -> Self {
+        self.register(name);
+        self
+    }
+
+    /// Register an extension, returns the id the peer uses for messages to us
+    pub fn register(&mut self, name: &'static str) -> u8 {
+        if let Some(id) = self.local_id(name) {
+            return id;
+        }
+        self.local.push(name);
+        self.local.len() as u8
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.local
+            .iter()
+            .position(|&local| local == name)
+            .map(|i| i as u8 + 1)
+    }
+
+    /// Whether the peer announced support for `name` in its extended handshake
+    pub fn supports(&self, name: &str) -> bool {
+        self.remote.contains_key(name)
+    }
+
+    /// The extended handshake of the peer, once received
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer.as_ref()
+    }
+
+    /// Our extended handshake listing the registered extensions, to be completed
+    /// with extension specific fields like `metadata_size` before sending
+    pub fn handshake(&self) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: self
+                .local
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_string(), i as i64 + 1))
+                .collect(),
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(REQUEST_QUEUE),
+            ..Default::default()
+        }
+    }
+
+    pub fn handshake_message(handshake: &ExtendedHandshake) -> Result<Message> {
+        Ok(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: Bytes::from(handshake.to_bytes()?),
+        })
+    }
+
+    /// A message for extension `name`, addressed with the id the peer assigned to it
+    pub fn message(&self, name: &str, payload: Bytes) -> Result<Message> {
+        let id = *self
+            .remote
+            .get(name)
+            .ok_or(eyre!("peer does not support {name}"))?;
+        Ok(Message::Extended { id, payload })
+    }
+
+    /// Route an extended message received from the peer
+    pub fn receive(&mut self, id: u8, payload: Bytes) -> Result<Extended> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::from_bytes(&payload)?;
+            // A later handshake may update ids, 0 disables an extension
+            for (name, &id) in &handshake.m {
+                match u8::try_from(id) {
+                    Ok(0) | Err(_) => self.remote.remove(name),
+                    Ok(id) => self.remote.insert(name.clone(), id),
+                };
+            }
+            self.peer = Some(handshake.clone());
+            return Ok(Extended::Handshake(handshake));
+        }
+
+        let name = id
+            .checked_sub(1)
+            .and_then(|i| self.local.get(i as usize))
+            .ok_or(eyre!("extended message for unknown id {id}"))?;
+        Ok(Extended::Message { name, payload })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_handshake_round_trips() {
+        let mut handshake = ExtensionRegistry::new()
+            .with("ut_metadata")
+            .with("ut_pex")
+            .handshake();
+        handshake.metadata_size = Some(31_235);
+        handshake.set_your_ip("10.0.0.1".parse().unwrap());
+
+        let decoded = ExtendedHandshake::from_bytes(&handshake.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, handshake);
+        assert_eq!(decoded.m["ut_metadata"], 1);
+        assert_eq!(decoded.m["ut_pex"], 2);
+        assert_eq!(decoded.your_ip(), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(decoded.v.as_deref(), Some(CLIENT_VERSION));
+    }
+
+    #[test]
+    fn parses_handshakes_of_other_clients() {
+        // Unknown keys are ignored, everything but `m` is optional
+        let handshake =
+            ExtendedHandshake::from_bytes(b"d1:md11:lt_donthavei7e6:ut_pexi1ee1:pi6881ee").unwrap();
+        assert_eq!(handshake.m.len(), 2);
+        assert_eq!(handshake.v, None);
+        assert_eq!(handshake.your_ip(), None);
+    }
+
+    #[test]
+    fn routes_messages_by_name() {
+        let mut registry = ExtensionRegistry::new().with("ut_metadata").with("ut_pex");
+        assert!(registry.message("ut_pex", Bytes::new()).is_err());
+
+        let payload = Bytes::from_static(b"d1:md6:ut_pexi5e11:ut_metadatai9eee");
+        let Extended::Handshake(_) = registry.receive(HANDSHAKE_ID, payload).unwrap() else {
+            panic!("expected the extended handshake");
+        };
+        assert!(registry.supports("ut_metadata"));
+        assert_eq!(
+            registry
+                .message("ut_pex", Bytes::from_static(b"x"))
+                .unwrap(),
+            Message::Extended {
+                id: 5,
+                payload: Bytes::from_static(b"x")
+            }
+        );
+
+        // Messages to us use our ids
+        assert_eq!(
+            registry.receive(2, Bytes::from_static(b"y")).unwrap(),
+            Extended::Message {
+                name: "ut_pex",
+                payload: Bytes::from_static(b"y")
+            }
+        );
+        assert!(registry.receive(3, Bytes::new()).is_err());
+
+        // The peer disables ut_pex
+        let payload = Bytes::from_static(b"d1:md6:ut_pexi0eee");
+        registry.receive(HANDSHAKE_ID, payload).unwrap();
+        assert!(!registry.supports("ut_pex"));
+        assert!(registry.supports("ut_metadata"));
+    }
+}
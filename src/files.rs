@@ -0,0 +1,150 @@
+use std::path::{Component, Path, PathBuf};
+
+use eyre::{eyre, Result};
+
+use crate::Info;
+
+/// A file of the torrent placed on disk, with its position in the concatenated torrent data
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A contiguous part of a byte range of the torrent that lives in a single file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSpan {
+    pub file: usize,
+    pub file_offset: u64,
+    /// Offset of the span within the requested range
+    pub range_offset: usize,
+    pub length: usize,
+}
+
+/// Maps the torrent's byte stream onto its files.
+/// A single-file torrent is written to `output_path` itself, the files of a
+/// multi-file torrent are created under the `output_path` directory.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub files: Vec<FileEntry>,
+}
+
+impl FileLayout {
+    pub fn new<T>(output_path: T, info: &Info) -> Result<Self>
+    where
+        T: AsRef<Path>,
+    {
+        let output_path = output_path.as_ref();
+        let mut offset = 0;
+        let mut files = vec![];
+
+        for file in info.files() {
+            let path = if info.is_multi_file() {
+                let mut path = output_path.to_path_buf();
+                for component in &file.path {
+                    // Refuse paths that would escape the output directory
+                    match Path::new(component).components().next() {
+                        Some(Component::Normal(_)) if !component.contains(['/', '\\']) => {
+                            path.push(component)
+                        }
+                        _ => return Err(eyre!("Invalid path component: {component}")),
+                    }
+                }
+                path
+            } else {
+                output_path.to_path_buf()
+            };
+
+            let length = file.length as u64;
+            files.push(FileEntry {
+                path,
+                offset,
+                length,
+            });
+            offset += length;
+        }
+
+        Ok(Self { files })
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// Split the torrent byte range `[offset, offset + length)` into per-file spans
+    pub fn spans(&self, offset: u64, length: usize) -> Vec<FileSpan> {
+        let end = offset + length as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && offset < file.offset + file.length)
+            .map(|(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                FileSpan {
+                    file: index,
+                    file_offset: start - file.offset,
+                    range_offset: (start - offset) as usize,
+                    length: (stop - start) as usize,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileInfo;
+
+    fn multi_file_info() -> Info {
+        Info {
+            name: "dir".to_string(),
+            length: 12,
+            files: Some(vec![
+                FileInfo {
+                    length: 5,
+                    path: vec!["a".to_string()],
+                },
+                FileInfo {
+                    length: 7,
+                    path: vec!["sub".to_string(), "b".to_string()],
+                },
+            ]),
+            piece_length: 4,
+            pieces: vec![0; 60],
+        }
+    }
+
+    #[test]
+    fn piece_straddling_files_is_split() {
+        let layout = FileLayout::new("out", &multi_file_info()).unwrap();
+
+        assert_eq!(
+            layout.spans(4, 4),
+            vec![
+                FileSpan {
+                    file: 0,
+                    file_offset: 4,
+                    range_offset: 0,
+                    length: 1,
+                },
+                FileSpan {
+                    file: 1,
+                    file_offset: 0,
+                    range_offset: 1,
+                    length: 3,
+                },
+            ]
+        );
+        assert_eq!(layout.files[1].path, PathBuf::from("out/sub/b"));
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        let mut info = multi_file_info();
+        info.files.as_mut().unwrap()[0].path = vec!["..".to_string(), "a".to_string()];
+        assert!(FileLayout::new("out", &info).is_err());
+    }
+}
@@ -1,11 +1,17 @@
-use crate::{parse::Parser, peers::Peer};
-use eyre::{Context, ContextCompat, Result};
-use std::collections::HashMap;
+use crate::{
+    error::{Error, Result},
+    parse::Metainfo,
+    peers::Peer,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
 
+/// Byte and mask of the reserved bit announcing the extension protocol (BEP 10)
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+
 #[repr(C)]
 pub struct Handshake {
     pub length: u8,
@@ -16,16 +22,30 @@ pub struct Handshake {
 }
 
 impl Handshake {
+    /// A handshake announcing support for the extension protocol
     fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
+    /// Whether the handshake names the BitTorrent protocol
+    fn is_bittorrent(&self) -> bool {
+        self.length == 19 && &self.bittorrent == b"BitTorrent protocol"
+    }
+
+    /// Whether the sender set the extension protocol bit
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Handshake is a POD with repr(c)
@@ -33,18 +53,18 @@ impl Handshake {
         bytes
     }
 
-    pub async fn peer_handshake(
-        dictionary: &HashMap<Vec<u8>, serde_bencode::value::Value>,
-        peer: Peer,
-    ) -> Result<(TcpStream, Handshake)> {
-        let info_hash_value = dictionary.get(b"info".as_ref()).context("no info")?;
-        let info_hash = Parser::get_info_hash_array(info_hash_value)?;
+    pub async fn peer_handshake(metainfo: &Metainfo, peer: Peer) -> Result<(TcpStream, Handshake)> {
+        Handshake::connect(metainfo.info_hash(), peer).await
+    }
 
+    /// Connect to a peer and exchange handshakes for the torrent identified by `info_hash`
+    pub async fn connect(info_hash: [u8; 20], peer: Peer) -> Result<(TcpStream, Handshake)> {
         tracing::info!("ip: {}, port: {}", peer.0.ip(), peer.0.port());
+        let addr = peer.0;
 
-        let mut peer = tokio::net::TcpStream::connect(peer.0)
+        let mut peer = tokio::net::TcpStream::connect(addr)
             .await
-            .context("connect to peer")?;
+            .map_err(|e| Error::handshake(addr, e))?;
 
         let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
 
@@ -58,22 +78,71 @@ impl Handshake {
 
             peer.write_all(handshake_bytes)
                 .await
-                .context("write handshake")?;
+                .map_err(|e| Error::handshake(addr, e))?;
 
             peer.read_exact(handshake_bytes)
                 .await
-                .context("read handshake")?;
+                .map_err(|e| Error::handshake(addr, e))?;
         }
 
-        assert_eq!(handshake.length, 19);
-
-        assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
+        if !handshake.is_bittorrent() {
+            return Err(Error::handshake(addr, "not a BitTorrent handshake"));
+        }
+        if handshake.info_hash != info_hash {
+            return Err(Error::handshake(addr, "peer answered for another torrent"));
+        }
 
         println!("Peer ID: {}", hex::encode(handshake.peer_id));
 
         Ok((peer, handshake))
     }
 
+    /// Connect to a peer that has to support the extension protocol
+    pub async fn connect_extended(
+        info_hash: [u8; 20],
+        peer: Peer,
+    ) -> Result<(TcpStream, Handshake)> {
+        let addr = peer.0;
+        let (stream, handshake) = Handshake::connect(info_hash, peer).await?;
+        if !handshake.supports_extensions() {
+            return Err(Error::handshake(
+                addr,
+                "peer does not support the extension protocol",
+            ));
+        }
+        Ok((stream, handshake))
+    }
+
+    /// Answer the handshake of an inbound peer.
+    /// The connection is refused unless `knows` accepts the requested info hash.
+    pub async fn accept(
+        peer: &mut TcpStream,
+        knows: impl Fn(&[u8; 20]) -> bool,
+    ) -> Result<Handshake> {
+        let addr = peer.peer_addr()?;
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        peer.read_exact(handshake.as_bytes_mut())
+            .await
+            .map_err(|e| Error::handshake(addr, e))?;
+
+        if !handshake.is_bittorrent() {
+            return Err(Error::handshake(addr, "not a BitTorrent handshake"));
+        }
+        if !knows(&handshake.info_hash) {
+            return Err(Error::handshake(
+                addr,
+                format!("unknown info hash {}", hex::encode(handshake.info_hash)),
+            ));
+        }
+
+        let mut reply = Handshake::new(handshake.info_hash, *b"00112233445566778899");
+        peer.write_all(reply.as_bytes_mut())
+            .await
+            .map_err(|e| Error::handshake(addr, e))?;
+
+        Ok(handshake)
+    }
+
     pub async fn get_handshake<'a>(
         peer: &mut TcpStream,
         handshake: &'a mut Handshake,
@@ -87,13 +156,9 @@ impl Handshake {
             let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
                 unsafe { &mut *handshake_bytes };
 
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
+            peer.write_all(handshake_bytes).await?;
 
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
+            peer.read_exact(handshake_bytes).await?;
         }
         Ok(handshake)
     }
@@ -1,37 +1,140 @@
 use std::{
+    collections::HashMap,
     fmt,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
 };
 
+use error::Error;
+use futures_util::future::join_all;
 use peers::Peer;
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
+use serde_bytes::ByteBuf;
 
+pub mod bencode;
+pub mod choker;
+pub mod codec;
 pub mod decode;
+pub mod dht;
 pub mod downloader;
 pub mod encode;
+pub mod error;
+pub mod extension;
+pub mod files;
 pub mod handshake;
+pub mod magnet;
+pub mod metadata;
 pub mod parse;
 pub mod peer_message;
 pub mod peers;
+pub mod pex;
+pub mod picker;
+pub mod resume;
+pub mod seeder;
+pub mod storage;
+pub mod swarm;
+pub mod tracker;
+pub mod udp_tracker;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Info {
     pub name: String,
+    /// Total length in bytes; for multi-file torrents the sum of all `files`
+    #[serde(default)]
     pub length: i64,
+    /// Present only for multi-file torrents, `name` is then the directory name
+    #[serde(default)]
+    pub files: Option<Vec<FileInfo>>,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
+    /// Concatenated SHA-1 hashes, a byte string in bencode
+    #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A torrent has either `length` or `files`, never both (BEP 3)
+impl Serialize for Info {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut info = serializer.serialize_struct("Info", 4)?;
+        info.serialize_field("name", &self.name)?;
+        match &self.files {
+            Some(files) => info.serialize_field("files", files)?,
+            None => info.serialize_field("length", &self.length)?,
+        }
+        info.serialize_field("piece length", &self.piece_length)?;
+        info.serialize_field("pieces", serde_bytes::Bytes::new(&self.pieces))?;
+        info.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub length: i64,
+    /// Path components relative to the torrent directory
+    pub path: Vec<String>,
+}
+
+impl Info {
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    /// The files of the torrent in piece order, a single-file torrent is one file named `name`
+    pub fn files(&self) -> Vec<FileInfo> {
+        match &self.files {
+            Some(files) => files.clone(),
+            None => vec![FileInfo {
+                length: self.length,
+                path: vec![self.name.clone()],
+            }],
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    /// Size of a piece, the last one may be shorter than `piece_length`
+    pub fn piece_size(&self, index: usize) -> i64 {
+        self.piece_length
+            .min(self.length - self.piece_length * index as i64)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TorrentResponse {
     pub info: Info,
     #[serde(rename = "announce")]
     pub announce_url: String,
+    /// Tiers of tracker URLs (BEP 12), takes precedence over `announce` when present
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
     pub hash: String,
+    /// The info dictionary exactly as the torrent encoded it, `info` drops unknown keys
+    #[serde(skip)]
+    pub raw_info: Vec<u8>,
+}
+
+impl TorrentResponse {
+    /// The info hash as raw bytes
+    pub fn info_hash(&self) -> error::Result<[u8; 20]> {
+        hex::decode(&self.hash)
+            .map_err(Error::metainfo)?
+            .try_into()
+            .map_err(|_| Error::metainfo("Hash length mismatch"))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -49,32 +152,273 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<TrackerEvent>,
+    /// Echoes the `tracker id` of an earlier response
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+    /// Our IPv6 address, for trackers reached over IPv4 (BEP 7)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl TrackerRequest {
+    /// A regular announce with nothing transferred yet
+    pub fn new(left: usize) -> Self {
+        Self {
+            peer_id: String::from("00112233445566778899"),
+            port: seeder::DEFAULT_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+            event: None,
+            tracker_id: None,
+            ipv6: peers::local_ipv6(),
+        }
+    }
+}
+
+/// Announces other than the regular ones sent every interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerEvent {
+    Started,
+    Completed,
+    Stopped,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl TrackerEvent {
+    /// The event field of a UDP announce, 0 is a regular announce
+    pub fn udp_code(event: Option<TrackerEvent>) -> u32 {
+        match event {
+            None => 0,
+            Some(TrackerEvent::Completed) => 1,
+            Some(TrackerEvent::Started) => 2,
+            Some(TrackerEvent::Stopped) => 3,
+        }
+    }
+}
+
+/// A tracker response, only `failure reason` is present if the announce failed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "RawTrackerResponse")]
 pub struct TrackerResponse {
     pub interval: usize,
+    /// Announces must not be sent more often than this
+    pub min_interval: Option<usize>,
+    /// IPv4 and IPv6 peers
     pub peers: Peers,
+    /// Peer ids by address, for trackers that list peers as dictionaries
+    pub peer_ids: HashMap<SocketAddr, [u8; 20]>,
+    /// Listed peers whose `ip` is a hostname, see [`TrackerResponse::resolve_hostnames`]
+    pub hostnames: Vec<PeerEntry>,
+    /// To be sent back on the next announce
+    pub tracker_id: Option<String>,
+    pub failure_reason: Option<String>,
+    pub warning_message: Option<String>,
+}
+
+/// The bencoded response, IPv6 peers come in a separate `peers6` string (BEP 7)
+#[derive(Deserialize)]
+struct RawTrackerResponse {
+    #[serde(default)]
+    interval: usize,
+    #[serde(rename = "min interval", default)]
+    min_interval: Option<usize>,
+    #[serde(default)]
+    peers: PeerList,
+    #[serde(default, deserialize_with = "Peers::deserialize_compact6")]
+    peers6: Peers,
+    #[serde(rename = "tracker id", default)]
+    tracker_id: Option<String>,
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message", default)]
+    warning_message: Option<String>,
+}
+
+impl From<RawTrackerResponse> for TrackerResponse {
+    fn from(raw: RawTrackerResponse) -> Self {
+        let mut response = TrackerResponse {
+            interval: raw.interval,
+            min_interval: raw.min_interval,
+            tracker_id: raw.tracker_id,
+            failure_reason: raw.failure_reason,
+            warning_message: raw.warning_message,
+            ..Default::default()
+        };
+
+        match raw.peers {
+            PeerList::Compact(peers) => response.peers = peers,
+            PeerList::Entries(entries) => {
+                for entry in entries {
+                    match entry.ip.parse::<IpAddr>() {
+                        Ok(ip) => response.add_peer(SocketAddr::new(ip, entry.port), &entry),
+                        Err(_) => response.hostnames.push(entry),
+                    }
+                }
+            }
+        }
+        response.peers.0.extend(raw.peers6.0);
+        response
+    }
+}
+
+/// Time a peer hostname gets to resolve
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl TrackerResponse {
+    /// Resolve the hostnames some trackers put into `ip`, all at once, adding their
+    /// addresses to `peers`. Hostnames that do not resolve in time are dropped.
+    pub async fn resolve_hostnames(&mut self) {
+        let entries = std::mem::take(&mut self.hostnames);
+        let lookups = entries.iter().map(|entry| {
+            tokio::time::timeout(
+                RESOLVE_TIMEOUT,
+                tokio::net::lookup_host((entry.ip.as_str(), entry.port)),
+            )
+        });
+
+        for (entry, lookup) in entries.iter().zip(join_all(lookups).await) {
+            match lookup {
+                Ok(Ok(mut addrs)) => {
+                    if let Some(addr) = addrs.next() {
+                        self.add_peer(addr, entry);
+                    }
+                }
+                Ok(Err(e)) => tracing::debug!("Cannot resolve peer {}: {}", entry.ip, e),
+                Err(_) => tracing::debug!("Resolving peer {} timed out", entry.ip),
+            }
+        }
+    }
+
+    fn add_peer(&mut self, addr: SocketAddr, entry: &PeerEntry) {
+        self.peers.0.push(addr);
+        if let Some(peer_id) = entry.peer_id() {
+            self.peer_ids.insert(addr, peer_id);
+        }
+    }
+}
+
+/// A peer of a non-compact tracker response
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PeerEntry {
+    /// IPv4 or IPv6 address, or a hostname
+    pub ip: String,
+    pub port: u16,
+    #[serde(rename = "peer id", default)]
+    pub peer_id: Option<ByteBuf>,
+}
+
+impl PeerEntry {
+    /// The peer id, if the tracker sent a valid one
+    pub fn peer_id(&self) -> Option<[u8; 20]> {
+        let peer_id: &[u8] = self.peer_id.as_deref()?;
+        peer_id.try_into().ok()
+    }
+}
+
+/// `peers` is a compact string, or a list of dictionaries from trackers that ignore `compact=1`
+enum PeerList {
+    Compact(Peers),
+    Entries(Vec<PeerEntry>),
 }
 
-#[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(Peers::default())
+    }
+}
 
-struct PeersVisitor;
+struct PeerListVisitor;
+
+impl<'de> Visitor<'de> for PeerListVisitor {
+    type Value = PeerList;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("compact peers or a list of peer dictionaries")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Peers::from_compact(v)
+            .map(PeerList::Compact)
+            .map_err(E::custom)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut entries = vec![];
+        while let Some(entry) = seq.next_element()? {
+            entries.push(entry);
+        }
+        Ok(PeerList::Entries(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(PeerListVisitor)
+    }
+}
+
+/// Swarm health of one torrent as reported by a tracker scrape
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with the complete torrent, `complete` in HTTP scrapes
+    pub seeders: usize,
+    /// Peers still downloading, `incomplete` in HTTP scrapes
+    pub leechers: usize,
+    /// Downloads the tracker has seen finish, `downloaded` in HTTP scrapes
+    pub completed: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
+
+struct PeersVisitor {
+    ipv6: bool,
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+        if self.ipv6 {
+            formatter.write_str("18 bytes, the first 16 bytes are a peer's IPv6 address and the last 2 are a peer's port number")
+        } else {
+            formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+        }
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if v.len() % 6 != 0 {
-            return Err(E::custom(format!("length is {}", v.len())));
+        if self.ipv6 {
+            Peers::from_compact6(v).map_err(E::custom)
+        } else {
+            Peers::from_compact(v).map_err(E::custom)
+        }
+    }
+}
+
+impl Peers {
+    /// Decode the compact peer format, 6 bytes per peer
+    pub fn from_compact(v: &[u8]) -> error::Result<Self> {
+        if !v.len().is_multiple_of(6) {
+            return Err(Error::protocol(format!(
+                "compact peers length is {}",
+                v.len()
+            )));
         }
 
         // TODO: use array_chunks when stable; then we can also pattern-match in closure args
@@ -82,14 +426,73 @@ impl<'de> Visitor<'de> for PeersVisitor {
         Ok(Peers(
             v.chunks_exact(6)
                 .map(|slice_6| {
-                    SocketAddrV4::new(
+                    SocketAddr::V4(SocketAddrV4::new(
                         Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
                         u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                    )
+                    ))
+                })
+                .collect(),
+        ))
+    }
+
+    /// Decode the compact IPv6 peer format, 16 bytes of address and 2 of port per peer
+    pub fn from_compact6(v: &[u8]) -> error::Result<Self> {
+        if !v.len().is_multiple_of(18) {
+            return Err(Error::protocol(format!(
+                "compact peers length is {}",
+                v.len()
+            )));
+        }
+
+        Ok(Peers(
+            v.chunks_exact(18)
+                .map(|slice_18| {
+                    let octets: [u8; 16] = slice_18[..16].try_into().unwrap();
+                    SocketAddr::V6(SocketAddrV6::new(
+                        Ipv6Addr::from(octets),
+                        u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                        0,
+                        0,
+                    ))
                 })
                 .collect(),
         ))
     }
+
+    /// Encode the IPv4 peers in the compact format, IPv6 peers are left out
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut single_slice = Vec::with_capacity(6 * self.0.len());
+
+        for peer in &self.0 {
+            if let SocketAddr::V4(peer) = peer {
+                single_slice.extend(peer.ip().octets());
+                single_slice.extend(peer.port().to_be_bytes());
+            }
+        }
+
+        single_slice
+    }
+
+    /// Encode the IPv6 peers in the compact format, IPv4 peers are left out
+    pub fn to_compact6(&self) -> Vec<u8> {
+        let mut single_slice = Vec::new();
+
+        for peer in &self.0 {
+            if let SocketAddr::V6(peer) = peer {
+                single_slice.extend(peer.ip().octets());
+                single_slice.extend(peer.port().to_be_bytes());
+            }
+        }
+
+        single_slice
+    }
+
+    fn deserialize_compact6<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PeersVisitor { ipv6: true })
+    }
 }
 
 impl<'de> Deserialize<'de> for Peers {
@@ -97,7 +500,7 @@ impl<'de> Deserialize<'de> for Peers {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_bytes(PeersVisitor { ipv6: false })
     }
 }
 
@@ -106,21 +509,20 @@ impl Serialize for Peers {
     where
         S: Serializer,
     {
-        let mut single_slice = Vec::with_capacity(6 * self.0.len());
-
-        for peer in &self.0 {
-            single_slice.extend(peer.ip().octets());
-
-            single_slice.extend(peer.port().to_be_bytes());
-        }
-
-        serializer.serialize_bytes(&single_slice)
+        serializer.serialize_bytes(&self.to_compact())
     }
 }
 // Implement conversion from Peers to Peer
-impl From<Peers> for Peer {
-    fn from(peers: Peers) -> Self {
+impl TryFrom<Peers> for Peer {
+    type Error = Error;
+
+    fn try_from(peers: Peers) -> error::Result<Self> {
         // Assuming we want the first peer in the list
-        Peer(peers.0.into_iter().next().expect("Peers list is empty"))
+        peers
+            .0
+            .into_iter()
+            .next()
+            .map(Peer)
+            .ok_or_else(|| Error::MissingField("peers".to_string()))
     }
 }
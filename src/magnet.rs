@@ -0,0 +1,155 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use eyre::{eyre, Context, Result};
+use url::Url;
+
+/// A magnet URI
+/// https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name, `dn`
+    pub name: Option<String>,
+    /// Tracker URLs, `tr`
+    pub trackers: Vec<String>,
+    /// Peer addresses, `x.pe`
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).context("parse magnet URI")?;
+        if url.scheme() != "magnet" {
+            return Err(eyre!("not a magnet URI: {uri}"));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // Other URNs (e.g. btmh for v2 torrents) are not supported
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(Magnet::parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => tracing::debug!("Skipping unsupported peer address {}", value),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or(eyre!("magnet URI has no urn:btih info hash"))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn hash(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+
+    /// The info hash is 40 hex digits or 32 base32 characters
+    fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+        let bytes = match hash.len() {
+            40 => hex::decode(hash).context("hex info hash")?,
+            32 => base32_decode(hash)?,
+            length => return Err(eyre!("info hash has {length} characters")),
+        };
+        bytes
+            .try_into()
+            .map_err(|_| eyre!("info hash is not 20 bytes"))
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Magnet::parse(s)
+    }
+}
+
+/// RFC 4648 base32 without padding, case-insensitive
+fn base32_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(eyre!("invalid base32 character {:?}", c as char)),
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn parses_hex_magnet() {
+        let magnet: Magnet = format!(
+            "magnet:?xt=urn:btih:{INFO_HASH}&dn=sample.txt\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A6969&x.pe=127.0.0.1:6881&x.pe=%5B2001:db8::1%5D:6881&x.pe=example.com:6881"
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(magnet.hash(), INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "udp://tracker.example:6969"
+            ]
+        );
+        assert_eq!(
+            magnet.peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_base32_magnet() {
+        // Base32 of the info hash above
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(magnet.hash(), INFO_HASH);
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_magnets() {
+        assert!(Magnet::parse("http://example.com/?xt=urn:btih:abc").is_err());
+        assert!(Magnet::parse("magnet:?dn=no-hash").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1").is_err());
+    }
+}
@@ -1,11 +1,36 @@
-use eyre::Result;
-use std::env;
+use eyre::{ContextCompat, Result};
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+use tokio::{net::TcpListener, sync::mpsc};
 
 use bittorrent_rust::{
-    decode::Decoder, downloader::Downloader, encode::Encoder, handshake::Handshake, parse::Parser,
+    codec::PeerCodec,
+    decode::{ByteEncoding, Decoder},
+    dht::{Dht, BOOTSTRAP_NODES},
+    downloader::Downloader,
+    encode::Encoder,
+    handshake::Handshake,
+    magnet::Magnet,
+    metadata::Metadata,
+    parse::{Metainfo, Parser},
     peers::Peer,
+    seeder::{Seeder, DEFAULT_PORT},
+    storage::StorageKind,
+    swarm::Swarm,
+    tracker::{AnnounceList, TorrentStats, TrackerSession},
+    Peers,
 };
 
+/// Where the DHT keeps its node id and routing table between runs
+const DHT_STATE_FILE: &str = "dht.state";
+
+/// Environment variable selecting the storage backend: sparse (default), preallocated or mmap
+const STORAGE_VAR: &str = "BITTORRENT_STORAGE";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt().init();
@@ -15,52 +40,121 @@ async fn main() -> Result<()> {
 
     match command.as_str() {
         "decode" => {
-            tracing::info!("Decode command started");
-            let encoded_value = &args[2];
-            let decoded_value = Decoder::decode_bencoded_value(encoded_value)?;
-            tracing::info!("{}", decoded_value);
+            tracing::debug!("Decode command started");
+            // decode [--json | --pretty] [--bytes=hex|base64|escaped] [<value> | -]
+            let (mut json, mut pretty) = (false, false);
+            let mut bytes = ByteEncoding::default();
+            let mut encoded_value = None;
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--json" => json = true,
+                    "--pretty" => pretty = true,
+                    _ => match arg.strip_prefix("--bytes=") {
+                        Some(encoding) => bytes = encoding.parse()?,
+                        None => encoded_value = Some(arg),
+                    },
+                }
+            }
+            // Without a value, or with `-`, the bencode is read from stdin
+            let encoded_value = match encoded_value {
+                Some(value) if value != "-" => value.as_bytes().to_vec(),
+                _ => {
+                    let mut value = vec![];
+                    io::stdin().read_to_end(&mut value)?;
+                    value
+                }
+            };
+
+            let decoded_value = Decoder::to_json(&encoded_value, bytes)?;
+            if pretty {
+                println!("{}", serde_json::to_string_pretty(&decoded_value)?);
+            } else if json {
+                println!("{}", decoded_value);
+            } else {
+                tracing::info!("{}", decoded_value);
+            }
         }
         "info" => {
             let file_name = &args[2];
-            let torrent_dict = Parser::read_torrent_file(file_name)?;
-            let torrent = Parser::parse_torrent_file(&torrent_dict)?;
+            let (metainfo, _) = read_torrent(file_name).await?;
+            let torrent = Parser::parse_torrent_file(&metainfo)?;
             tracing::info!("Tracker URL: {}", torrent.announce_url);
             tracing::info!("Length: {}", torrent.info.length);
+            if torrent.info.is_multi_file() {
+                tracing::info!("Files:");
+                for file in torrent.info.files() {
+                    tracing::info!("  {} ({} bytes)", file.path.join("/"), file.length);
+                }
+            }
             tracing::info!("Info Hash: {}", torrent.hash);
             tracing::info!("Piece Length: {}", torrent.info.piece_length);
-            Parser::split_and_display_sha1_hashes(torrent.info.pieces);
+            Parser::split_and_display_sha1_hashes(torrent.info.pieces)?;
+        }
+        "magnet_parse" => {
+            let magnet = Magnet::parse(&args[2])?;
+            for tracker in &magnet.trackers {
+                tracing::info!("Tracker URL: {}", tracker);
+            }
+            if let Some(name) = &magnet.name {
+                tracing::info!("Name: {}", name);
+            }
+            tracing::info!("Info Hash: {}", magnet.hash());
         }
         "encode" => {
-            // let file_path = &args[2];
-            let file_path = "examples/example_file.txt";
-            let announce_url = "http://example.com/announce";
+            // encode [--bytes=hex|base64|escaped], JSON on stdin, bencode on stdout
+            let bytes = match args.get(2).and_then(|arg| arg.strip_prefix("--bytes=")) {
+                Some(encoding) => encoding.parse()?,
+                None => ByteEncoding::default(),
+            };
+            let value: serde_json::Value = serde_json::from_reader(io::stdin())?;
+            let mut stdout = io::stdout();
+            stdout.write_all(&Encoder::encode_json(&value, bytes)?)?;
+            stdout.flush()?;
+        }
+        "create_torrent" => {
+            let file_path = &args[2];
+            let announce_url = &args[3];
             let piece_length = 512 * 1024; // 512 KB
-            Encoder::encode_file(file_path, announce_url, piece_length)?;
+            let output_path = format!("{file_path}.torrent");
+            Encoder::encode_file(file_path, announce_url, piece_length, &output_path)?;
         }
         "peers" => {
             let file_path = &args[2];
-            let torrent_dict = Parser::read_torrent_file(file_path)?;
-            Peer::discover_peers(&torrent_dict).await?;
+            let (metainfo, _) = read_torrent(file_path).await?;
+            let response = Peer::discover_peers(&metainfo).await?;
+            println!("Interval: {}", response.interval);
+            for peer in &response.peers.0 {
+                println!("{}:{}", peer.ip(), peer.port());
+            }
+        }
+        "scrape" => {
+            let file_path = &args[2];
+            let (metainfo, _) = read_torrent(file_path).await?;
+            let (tracker, stats) = Peer::scrape_torrent(&metainfo).await?;
+            println!("Tracker: {}", tracker);
+            println!("Seeders: {}", stats.seeders);
+            println!("Leechers: {}", stats.leechers);
+            println!("Completed: {}", stats.completed);
         }
         "handshake" => {
             let file_path = &args[2];
             let peer_addr = &args[3];
             let peer = peer_addr.parse::<Peer>()?;
-            let torrent_dict = Parser::read_torrent_file(file_path)?;
-            Handshake::peer_handshake(&torrent_dict, peer).await?;
+            let (metainfo, _) = read_torrent(file_path).await?;
+            Handshake::peer_handshake(&metainfo, peer).await?;
         }
         "download_piece" => {
             let output_path = &args[2];
             let file_path = &args[3];
             let piece_index = &args[4];
-            let torrent_dict = Parser::read_torrent_file(file_path)?;
-            let torrent_file = Parser::parse_torrent_file(&torrent_dict)?;
-            let tracker_response = Peer::discover_peers(&torrent_dict).await?;
-            let (mut peer, _handshake) =
-                Handshake::peer_handshake(&torrent_dict, tracker_response.peers.into()).await?;
+            let (metainfo, _) = read_torrent(file_path).await?;
+            let torrent_file = Parser::parse_torrent_file(&metainfo)?;
+            let tracker_response = Peer::discover_peers(&metainfo).await?;
+            let (peer, _handshake) =
+                Handshake::peer_handshake(&metainfo, tracker_response.peers.try_into()?).await?;
             Downloader::download_a_piece(
                 output_path,
-                &mut peer,
+                &mut PeerCodec::framed(peer),
                 &torrent_file,
                 &piece_index.parse::<i32>()?,
             )
@@ -69,16 +163,107 @@ async fn main() -> Result<()> {
         "download" => {
             let output_path = &args[2];
             let file_path = &args[3];
-            let torrent_dict = Parser::read_torrent_file(file_path)?;
-            let torrent_file = Parser::parse_torrent_file(&torrent_dict)?;
-            let tracker_response = Peer::discover_peers(&torrent_dict).await?;
-            let (mut peer, _handshake) =
-                Handshake::peer_handshake(&torrent_dict, tracker_response.peers.into()).await?;
-            Downloader::download_complete_pieces(output_path, &mut peer, &torrent_file).await?;
+            let (metainfo, mut peers) = read_torrent(file_path).await?;
+            let torrent_file = Parser::parse_torrent_file(&metainfo)?;
+            let info_hash = torrent_file.info_hash()?;
+
+            let stats = TorrentStats::new(torrent_file.info.length as u64);
+            let trackers = AnnounceList::from_torrent(&torrent_file);
+            let mut session = TrackerSession::new(trackers, info_hash, stats.clone());
+            // The peers of a magnet link go first, one of them just served the metadata
+            match find_peers(&mut session, info_hash).await {
+                Ok(found) => peers.0.extend(found.0),
+                Err(e) if !peers.0.is_empty() => tracing::warn!("No more peers: {:#}", e),
+                Err(e) => return Err(e),
+            }
+
+            let (announced, announced_peers) = mpsc::unbounded_channel();
+            let tracker = session.spawn(announced);
+            let result = Swarm::new()
+                .with_storage(storage_kind()?)
+                .download_tracked(output_path, torrent_file, peers, stats, announced_peers)
+                .await;
+            tracker.stop().await;
+            result?;
             tracing::info!("Downloaded {} to {}", file_path, output_path);
         }
+        "seed" => {
+            let file_path = &args[2];
+            let data_path = &args[3];
+            let (metainfo, _) = read_torrent(file_path).await?;
+            let torrent_file = Parser::parse_torrent_file(&metainfo)?;
+            let info_hash = torrent_file.info_hash()?;
+            let trackers = AnnounceList::from_torrent(&torrent_file);
+
+            let mut seeder = Seeder::new().with_storage(storage_kind()?);
+            seeder.add_torrent(torrent_file, data_path).await?;
+            // Dual-stack when the host has IPv6, IPv4 only otherwise
+            let listener = match TcpListener::bind(("::", DEFAULT_PORT)).await {
+                Ok(listener) => listener,
+                Err(_) => TcpListener::bind(("0.0.0.0", DEFAULT_PORT)).await?,
+            };
+
+            // Let the trackers know where to find us until we are interrupted
+            let stats = seeder.stats(&info_hash).context("torrent not seeded")?;
+            let mut session = TrackerSession::new(trackers, info_hash, stats);
+            if let Err(e) = session.start().await {
+                tracing::warn!("Announce failed: {:#}", e);
+            }
+            // Peers connect to us, the ones the trackers return are not needed
+            let tracker = session.spawn(mpsc::unbounded_channel().0);
+            let result = tokio::select! {
+                result = Arc::new(seeder).run(listener) => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            };
+            tracker.stop().await;
+            result?;
+        }
+        "dht_peers" => {
+            let file_path = &args[2];
+            let (metainfo, _) = read_torrent(file_path).await?;
+            let torrent_file = Parser::parse_torrent_file(&metainfo)?;
+            for peer in dht_peers(torrent_file.info_hash()?).await?.0 {
+                println!("{}", peer);
+            }
+        }
         _ => tracing::info!("unknown command: {}", args[1]),
     }
 
     Ok(())
 }
+
+/// Read a .torrent file, or fetch the metadata from peers when given a magnet link.
+/// The peers of a magnet link are returned too, a .torrent file comes without any.
+async fn read_torrent(torrent: &str) -> Result<(Metainfo, Peers)> {
+    if torrent.starts_with("magnet:") {
+        Metadata::resolve(&Magnet::parse(torrent)?).await
+    } else {
+        Ok((Parser::read_torrent_file(torrent)?, Peers(vec![])))
+    }
+}
+
+fn storage_kind() -> Result<StorageKind> {
+    match env::var(STORAGE_VAR) {
+        Ok(storage) => storage.parse(),
+        Err(_) => Ok(StorageKind::default()),
+    }
+}
+
+/// Announce `started` to the trackers, falling back to the DHT when they fail or know no peers
+async fn find_peers(session: &mut TrackerSession, info_hash: [u8; 20]) -> Result<Peers> {
+    match session.start().await {
+        Ok(response) if !response.peers.0.is_empty() => return Ok(response.peers),
+        Ok(_) => tracing::warn!("Tracker knows no peers, asking the DHT"),
+        Err(e) => tracing::warn!("Announce failed, asking the DHT: {:#}", e),
+    }
+    dht_peers(info_hash).await
+}
+
+async fn dht_peers(info_hash: [u8; 20]) -> Result<Peers> {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_PORT);
+    let dht = Dht::bind_with_state(addr, DHT_STATE_FILE).await?;
+    dht.bootstrap(BOOTSTRAP_NODES).await?;
+    let peers = dht.get_peers(info_hash).await;
+    dht.save(DHT_STATE_FILE)?;
+    Ok(Peers(peers.into_iter().map(SocketAddr::V4).collect()))
+}
@@ -0,0 +1,480 @@
+use std::{collections::HashMap, time::Duration};
+
+use bytes::Bytes;
+use eyre::{eyre, Context, ContextCompat, Result};
+use futures_util::SinkExt;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use tokio::time::timeout;
+
+use crate::{
+    bencode,
+    codec::{PeerCodec, PeerStream},
+    decode::Decoder,
+    downloader::Downloader,
+    extension::{Extended, ExtensionRegistry},
+    handshake::Handshake,
+    magnet::Magnet,
+    parse::Metainfo,
+    peer_message::Message,
+    peers::Peer,
+    tracker::AnnounceList,
+    Peers, TrackerRequest,
+};
+
+/// Metadata is exchanged in pieces of 16 KiB, only the last one may be shorter
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Refuse metadata larger than this, real info dictionaries stay far below
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Name of the extension in the extended handshake
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Time allowed to connect to a peer and receive the complete metadata from it
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// ut_metadata message types
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+/// A ut_metadata message, a bencoded header that data messages follow with the piece
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Bytes,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    /// Answer a request for `piece` of `metadata`
+    pub fn respond(metadata: &[u8], piece: usize) -> Self {
+        // `piece` comes from the peer, check it before it becomes an offset
+        if piece >= metadata.len().div_ceil(METADATA_PIECE_SIZE) {
+            return MetadataMessage::Reject { piece };
+        }
+        let begin = piece * METADATA_PIECE_SIZE;
+        let end = metadata.len().min(begin + METADATA_PIECE_SIZE);
+        MetadataMessage::Data {
+            piece,
+            total_size: metadata.len(),
+            data: Bytes::copy_from_slice(&metadata[begin..end]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (MSG_REQUEST, piece),
+            MetadataMessage::Data { piece, .. } => (MSG_DATA, piece),
+            MetadataMessage::Reject { piece } => (MSG_REJECT, piece),
+        };
+        let mut header = HashMap::from([
+            (b"msg_type".to_vec(), Value::Int(msg_type)),
+            (b"piece".to_vec(), Value::Int(*piece as i64)),
+        ]);
+
+        let MetadataMessage::Data {
+            total_size, data, ..
+        } = self
+        else {
+            return Ok(serde_bencode::to_bytes(&Value::Dict(header))?);
+        };
+        header.insert(b"total_size".to_vec(), Value::Int(*total_size as i64));
+        let mut bytes = serde_bencode::to_bytes(&Value::Dict(header))?;
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(payload: Bytes) -> Result<Self> {
+        let header_length = Decoder::value_length(&payload)?;
+        let header = Metadata::decode_dict(&payload[..header_length])?;
+        let piece = usize::try_from(Decoder::extract_int("piece", &header)?)
+            .context("negative metadata piece")?;
+
+        match Decoder::extract_int("msg_type", &header)? {
+            MSG_REQUEST => Ok(MetadataMessage::Request { piece }),
+            MSG_DATA => Ok(MetadataMessage::Data {
+                piece,
+                total_size: usize::try_from(Decoder::extract_int("total_size", &header)?)
+                    .context("negative metadata size")?,
+                data: payload.slice(header_length..),
+            }),
+            MSG_REJECT => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(eyre!("unknown ut_metadata message type {msg_type}")),
+        }
+    }
+}
+
+/// Fetches the info dictionary of a torrent from peers (BEP 9)
+/// https://www.bittorrent.org/beps/bep_0009.html
+pub struct Metadata;
+
+impl Metadata {
+    /// Turn a magnet link into the torrent `Parser::parse_torrent_file` consumes.
+    /// Peers come from the `x.pe` parameters and the trackers, the first one that
+    /// delivers metadata matching the info hash wins. They are returned along with the
+    /// torrent to download from, the one that delivered the metadata first.
+    pub async fn resolve(magnet: &Magnet) -> Result<(Metainfo, Peers)> {
+        let mut peers = magnet.peers.clone();
+        // Every `tr` is a tier of its own, the size is unknown until the metadata
+        // arrives and any non-zero value keeps trackers from taking us for a seeder
+        let mut trackers = AnnounceList::new(Metadata::tiers(magnet));
+        if !trackers.is_empty() {
+            match trackers
+                .announce(&magnet.info_hash, &TrackerRequest::new(1))
+                .await
+            {
+                Ok(response) => peers.extend(response.peers.0),
+                Err(e) => tracing::warn!("Announce failed: {:#}", e),
+            }
+        }
+
+        for (i, addr) in peers.clone().into_iter().enumerate() {
+            match Metadata::fetch(magnet.info_hash, Peer(addr)).await {
+                Ok(info) => {
+                    peers[..=i].rotate_right(1);
+                    return Ok((Metadata::metainfo(magnet, &info)?, Peers(peers)));
+                }
+                Err(e) => tracing::warn!("No metadata from {}: {:#}", addr, e),
+            }
+        }
+
+        Err(eyre!("no peer delivered the metadata of {}", magnet.hash()))
+    }
+
+    /// Fetch and verify the bencoded info dictionary from a single peer
+    pub async fn fetch(info_hash: [u8; 20], peer: Peer) -> Result<Vec<u8>> {
+        timeout(FETCH_TIMEOUT, async {
+            let (stream, _handshake) = Handshake::connect_extended(info_hash, peer).await?;
+            let mut stream = PeerCodec::framed(stream);
+            Metadata::fetch_from(&mut stream, info_hash).await
+        })
+        .await
+        .context("metadata fetch timed out")?
+    }
+
+    async fn fetch_from(stream: &mut PeerStream, info_hash: [u8; 20]) -> Result<Vec<u8>> {
+        let mut extensions = ExtensionRegistry::new().with(UT_METADATA);
+        stream
+            .send(ExtensionRegistry::handshake_message(
+                &extensions.handshake(),
+            )?)
+            .await?;
+
+        // The extended handshake of the peer tells us the size of the metadata
+        let size = loop {
+            let Message::Extended { id, payload } = Downloader::receive(stream).await? else {
+                continue;
+            };
+            if let Extended::Handshake(handshake) = extensions.receive(id, payload)? {
+                if !extensions.supports(UT_METADATA) {
+                    return Err(eyre!("peer does not support ut_metadata"));
+                }
+                break handshake.metadata_size.context("no metadata_size")?;
+            }
+        };
+
+        let size = usize::try_from(size).context("negative metadata size")?;
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(eyre!("invalid metadata size {size}"));
+        }
+        let num_pieces = size.div_ceil(METADATA_PIECE_SIZE);
+
+        for piece in 0..num_pieces {
+            let request = MetadataMessage::Request { piece }.to_bytes()?;
+            stream
+                .send(extensions.message(UT_METADATA, Bytes::from(request))?)
+                .await?;
+        }
+
+        let mut metadata = vec![0u8; size];
+        let mut received = vec![false; num_pieces];
+        while received.contains(&false) {
+            let Message::Extended { id, payload } = Downloader::receive(stream).await? else {
+                continue;
+            };
+            let Extended::Message {
+                name: UT_METADATA,
+                payload,
+            } = extensions.receive(id, payload)?
+            else {
+                continue;
+            };
+
+            let (piece, data) = match MetadataMessage::from_bytes(payload)? {
+                MetadataMessage::Data { piece, data, .. } => (piece, data),
+                MetadataMessage::Reject { piece } => {
+                    return Err(eyre!("peer rejected metadata piece {piece}"))
+                }
+                // Requests from the peer are not served
+                MetadataMessage::Request { .. } => continue,
+            };
+
+            if piece >= num_pieces {
+                return Err(eyre!("peer sent metadata piece {piece} of {num_pieces}"));
+            }
+            let begin = piece * METADATA_PIECE_SIZE;
+            let expected = METADATA_PIECE_SIZE.min(size - begin);
+            if data.len() != expected {
+                return Err(eyre!(
+                    "metadata piece {piece} has {} bytes, expected {expected}",
+                    data.len()
+                ));
+            }
+            metadata[begin..begin + data.len()].copy_from_slice(&data);
+            received[piece] = true;
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&metadata).into();
+        if hash != info_hash {
+            return Err(eyre!("metadata does not match the info hash"));
+        }
+
+        match bencode::parse(&metadata)?.value {
+            bencode::Value::Dict(_) => Ok(metadata),
+            _ => Err(eyre!("metadata is not a dictionary")),
+        }
+    }
+
+    /// The torrent of a magnet link once its info dictionary is known.
+    /// `info` is embedded as received, so the info hash stays that of the magnet link.
+    pub fn metainfo(magnet: &Magnet, info: &[u8]) -> Result<Metainfo> {
+        let announce = magnet.trackers.first().cloned().unwrap_or_default();
+        // Keys in sorted order: announce, announce-list, info
+        let mut torrent = b"d8:announce".to_vec();
+        Metadata::push_string(&mut torrent, announce.as_bytes());
+        if magnet.trackers.len() > 1 {
+            torrent.extend_from_slice(b"13:announce-listl");
+            for tier in Metadata::tiers(magnet) {
+                torrent.push(b'l');
+                for url in tier {
+                    Metadata::push_string(&mut torrent, url.as_bytes());
+                }
+                torrent.push(b'e');
+            }
+            torrent.push(b'e');
+        }
+        torrent.extend_from_slice(b"4:info");
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        Ok(Metainfo::from_bytes(torrent)?)
+    }
+
+    fn push_string(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    fn tiers(magnet: &Magnet) -> Vec<Vec<String>> {
+        magnet
+            .trackers
+            .iter()
+            .map(|url| vec![url.clone()])
+            .collect()
+    }
+
+    fn decode_dict(bytes: &[u8]) -> Result<HashMap<Vec<u8>, Value>> {
+        match serde_bencode::from_bytes(bytes)? {
+            Value::Dict(d) => Ok(d),
+            _ => Err(eyre!("Incorrect format, required dict")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::parse::Parser;
+
+    /// Serves `metadata` like a peer supporting ut_metadata would.
+    /// With `claimed_piece` every data message claims to hold that piece instead.
+    async fn serve_metadata(
+        listener: TcpListener,
+        metadata: Vec<u8>,
+        info_hash: [u8; 20],
+        claimed_piece: Option<i64>,
+    ) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(handshake[25] & 0x10, 0x10);
+        assert_eq!(&handshake[28..48], &info_hash);
+        socket.write_all(&handshake).await.unwrap();
+
+        let mut peer = PeerCodec::framed(socket);
+        peer.send(Message::Extended {
+            id: 0,
+            payload: Bytes::from(format!(
+                "d1:md11:ut_metadatai3ee13:metadata_sizei{}ee",
+                metadata.len()
+            )),
+        })
+        .await
+        .unwrap();
+
+        while let Some(Ok(message)) = peer.next().await {
+            let Message::Extended { id: 3, payload } = message else {
+                continue;
+            };
+            let MetadataMessage::Request { piece } = MetadataMessage::from_bytes(payload).unwrap()
+            else {
+                panic!("expected a metadata request");
+            };
+
+            let begin = piece * METADATA_PIECE_SIZE;
+            let end = metadata.len().min(begin + METADATA_PIECE_SIZE);
+            let mut data = format!(
+                "d8:msg_typei1e5:piecei{}e10:total_sizei{}ee",
+                claimed_piece.unwrap_or(piece as i64),
+                metadata.len()
+            )
+            .into_bytes();
+            data.extend_from_slice(&metadata[begin..end]);
+            peer.send(Message::Extended {
+                id: 1,
+                payload: Bytes::from(data),
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_metadata_in_pieces() {
+        // 1200 piece hashes make the metadata span two ut_metadata pieces
+        let mut metadata =
+            b"d6:lengthi1228800e4:name8:data.bin12:piece lengthi1024e6:pieces24000:".to_vec();
+        metadata.extend((0..24_000).map(|i| (i % 251) as u8));
+        metadata.push(b'e');
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, metadata.clone(), info_hash, None));
+
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&tr=http%3A%2F%2Ftracker%2Fannounce",
+            hex::encode(info_hash)
+        ))
+        .unwrap();
+        let info = Metadata::fetch(info_hash, Peer(addr)).await.unwrap();
+        assert_eq!(info, metadata);
+        let metainfo = Metadata::metainfo(&magnet, &info).unwrap();
+
+        let torrent = Parser::parse_torrent_file(&metainfo).unwrap();
+        assert_eq!(torrent.hash, magnet.hash());
+        assert_eq!(torrent.announce_url, "http://tracker/announce");
+        assert_eq!(torrent.info.length, 1_228_800);
+        assert_eq!(torrent.info.num_pieces(), 1200);
+    }
+
+    #[tokio::test]
+    async fn resolve_returns_the_peers_of_the_magnet_link() {
+        let metadata =
+            b"d6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:00000000000000000000e".to_vec();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, metadata, info_hash, None));
+        // Nobody listens here once the listener is dropped
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe={gone}&x.pe={addr}",
+            hex::encode(info_hash)
+        ))
+        .unwrap();
+        let (metainfo, peers) = Metadata::resolve(&magnet).await.unwrap();
+
+        assert_eq!(
+            Parser::parse_torrent_file(&metainfo).unwrap().hash,
+            magnet.hash()
+        );
+        // The peer that served the metadata comes first
+        assert_eq!(peers.0, vec![addr, gone]);
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_not_matching_info_hash() {
+        let metadata =
+            b"d6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:00000000000000000000e".to_vec();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, metadata, [7; 20], None));
+
+        assert!(Metadata::fetch([7; 20], Peer(addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_pieces_out_of_range() {
+        let metadata =
+            b"d6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:00000000000000000000e".to_vec();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(
+            listener,
+            metadata,
+            info_hash,
+            Some(i64::MAX),
+        ));
+
+        assert!(Metadata::fetch(info_hash, Peer(addr)).await.is_err());
+    }
+
+    #[test]
+    fn metadata_messages_round_trip() {
+        let metadata = vec![5u8; METADATA_PIECE_SIZE + 10];
+        let messages = vec![
+            MetadataMessage::Request { piece: 1 },
+            MetadataMessage::respond(&metadata, 1),
+            MetadataMessage::respond(&metadata, 2),
+        ];
+        assert_eq!(
+            messages[1],
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: METADATA_PIECE_SIZE + 10,
+                data: Bytes::from(vec![5u8; 10]),
+            }
+        );
+        assert_eq!(messages[2], MetadataMessage::Reject { piece: 2 });
+
+        // A request as far out as a peer can encode it is rejected, not wrapped around
+        let request = Bytes::from_static(b"d8:msg_typei0e5:piecei9223372036854775807ee");
+        let MetadataMessage::Request { piece } = MetadataMessage::from_bytes(request).unwrap()
+        else {
+            panic!("expected a metadata request");
+        };
+        assert_eq!(piece, i64::MAX as usize);
+        assert_eq!(
+            MetadataMessage::respond(&metadata, piece),
+            MetadataMessage::Reject { piece }
+        );
+
+        for message in messages {
+            let bytes = Bytes::from(message.to_bytes().unwrap());
+            assert_eq!(MetadataMessage::from_bytes(bytes).unwrap(), message);
+        }
+    }
+}
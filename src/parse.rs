@@ -1,55 +1,175 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::path::PathBuf;
 
-use eyre::{eyre, ContextCompat, Result};
-use serde_bencode::to_bytes;
 use sha1::{Digest, Sha1};
 
-use crate::{decode::Decoder, Info, TorrentResponse};
+use crate::{
+    bencode::{self, Node},
+    error::{Error, Result},
+    FileInfo, Info, TorrentResponse,
+};
+
+/// The bencoded bytes of a torrent file, kept as they are.
+/// The info hash is taken over the info dictionary exactly as it was encoded, which
+/// re-encoding a parsed copy would not reproduce for unsorted or non-canonical input.
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    bytes: Vec<u8>,
+    info_hash: [u8; 20],
+}
+
+impl Metainfo {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let root = bencode::parse(&bytes)?;
+        if root.as_dict().is_none() {
+            return Err(Error::metainfo("torrent is not a dictionary"));
+        }
+        let info_hash = Sha1::digest(root.get_dict("info")?.raw).into();
+
+        Ok(Self { bytes, info_hash })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+}
 
 pub struct Parser;
 impl Parser {
-    pub fn read_torrent_file<T>(
-        file_path: T,
-    ) -> Result<HashMap<Vec<u8>, serde_bencode::value::Value>>
+    pub fn read_torrent_file<T>(file_path: T) -> Result<Metainfo>
     where
         T: Into<PathBuf>,
     {
-        let content = std::fs::read(file_path.into())?;
-        let value: serde_bencode::value::Value = serde_bencode::from_bytes(content.as_slice())?;
-
-        match value {
-            serde_bencode::value::Value::Dict(d) => Ok(d),
-            _ => Err(eyre!("Incorrect format, required dict")),
-        }
+        Metainfo::from_bytes(std::fs::read(file_path.into())?)
     }
 
-    pub fn parse_torrent_file(
-        dictionary: &HashMap<Vec<u8>, serde_bencode::value::Value>,
-    ) -> Result<TorrentResponse> {
-        let announce = Decoder::extract_string("announce", dictionary)?;
-        let info = Decoder::extract_dict("info", dictionary)?;
+    pub fn parse_torrent_file(metainfo: &Metainfo) -> Result<TorrentResponse> {
+        let dictionary = bencode::parse(metainfo.as_bytes())?;
+        let announce_list = Parser::parse_announce_list(&dictionary)?;
+        // `announce` may be left out when there is an announce-list
+        let announce = match dictionary.get_str("announce") {
+            Ok(announce) => announce.to_string(),
+            Err(e) => announce_list.iter().flatten().next().cloned().ok_or(e)?,
+        };
+        let info = dictionary.get_dict("info")?;
+        let raw_info = info.raw.to_vec();
 
-        let info_hash = dictionary.get(b"info".as_ref()).context("no info")?;
-        let hash = hex::encode(Sha1::digest(serde_bencode::to_bytes(info_hash)?));
+        // Multi-file torrents have a list of files instead of a single length
+        let (length, files) = if info.get("files").is_some() {
+            let files = Parser::parse_files(info)?;
+            (files.iter().map(|file| file.length).sum(), Some(files))
+        } else {
+            (info.get_int("length")?, None)
+        };
+
+        let info = Info {
+            length,
+            files,
+            name: info.get_str("name")?.to_string(),
+            piece_length: info.get_int("piece length")?,
+            pieces: info.get_bytes("pieces")?.to_vec(),
+        };
+        Parser::validate(&info)?;
 
         Ok(TorrentResponse {
             announce_url: announce,
-            info: Info {
-                length: Decoder::extract_int("length", &info)?,
-                name: Decoder::extract_string("name", &info)?,
-                piece_length: Decoder::extract_int("piece length", &info)?,
-                pieces: Decoder::extract_bytes("pieces", &info)?,
-            },
-            hash,
+            announce_list,
+            info,
+            hash: hex::encode(metainfo.info_hash()),
+            raw_info,
         })
     }
 
-    pub fn split_and_display_sha1_hashes(pieces: Vec<u8>) {
+    /// Lengths become sizes and offsets on disk, refuse those that cannot describe a torrent
+    fn validate(info: &Info) -> Result<()> {
+        if info.files().iter().any(|file| file.length < 0) {
+            return Err(Error::metainfo("negative length"));
+        }
+        if info.piece_length <= 0 {
+            return Err(Error::metainfo(format!(
+                "invalid piece length {}",
+                info.piece_length
+            )));
+        }
+        if !info.pieces.len().is_multiple_of(20) {
+            return Err(Error::metainfo(
+                "the length of pieces must be a multiple of 20",
+            ));
+        }
+        let expected = (info.length as u64).div_ceil(info.piece_length as u64);
+        if info.num_pieces() as u64 != expected {
+            return Err(Error::metainfo(format!(
+                "{} piece hashes for {expected} pieces",
+                info.num_pieces()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The `announce-list` tiers (BEP 12), empty if the torrent has none
+    pub fn parse_announce_list(dictionary: &Node) -> Result<Vec<Vec<String>>> {
+        if dictionary.get("announce-list").is_none() {
+            return Ok(vec![]);
+        }
+
+        dictionary
+            .get_list("announce-list")?
+            .iter()
+            .map(|tier| {
+                tier.as_list()
+                    .ok_or_else(|| Error::metainfo("announce-list tier must be a list"))?
+                    .iter()
+                    .map(|url| {
+                        url.as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| Error::metainfo("tracker URL must be a string"))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn parse_files(info: &Node) -> Result<Vec<FileInfo>> {
+        info.get_list("files")?
+            .iter()
+            .map(|file| {
+                if file.as_dict().is_none() {
+                    return Err(Error::metainfo("file entry must be a dict"));
+                }
+
+                let path = file
+                    .get_list("path")?
+                    .iter()
+                    .map(|component| {
+                        component
+                            .as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| Error::metainfo("path component must be a string"))
+                    })
+                    .collect::<Result<Vec<String>>>()?;
+
+                if path.is_empty() {
+                    return Err(Error::metainfo("file path is empty"));
+                }
+
+                Ok(FileInfo {
+                    length: file.get_int("length")?,
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    pub fn split_and_display_sha1_hashes(pieces: Vec<u8>) -> Result<()> {
         // Ensure the length of pieces is a multiple of 20
-        assert!(
-            pieces.len() % 20 == 0,
-            "The length of pieces must be a multiple of 20"
-        );
+        if !pieces.len().is_multiple_of(20) {
+            return Err(Error::metainfo(
+                "the length of pieces must be a multiple of 20",
+            ));
+        }
         println!("Piece Hashes:");
         // Iterate over the pieces in chunks of 20 bytes
         for chunk in pieces.chunks(20) {
@@ -57,16 +177,7 @@ impl Parser {
             let hex_string = hex::encode(chunk);
             println!("{}", hex_string);
         }
-    }
-
-    pub fn get_info_hash_array(info_hash: &serde_bencode::value::Value) -> Result<[u8; 20]> {
-        let serialized_hash = to_bytes(&info_hash)?;
-        let sha1_hash = Sha1::digest(serialized_hash);
-        let info_hash_array: [u8; 20] = sha1_hash
-            .as_slice()
-            .try_into()
-            .map_err(|_| eyre!("Hash length mismatch"))?;
-        Ok(info_hash_array)
+        Ok(())
     }
 }
 
@@ -77,8 +188,8 @@ mod tests {
     #[test]
     fn parse_single_file_torrent() {
         let file_path = "sample.torrent";
-        let torrent_dict = Parser::read_torrent_file(file_path).unwrap();
-        let decoded_value = Parser::parse_torrent_file(&torrent_dict).unwrap();
+        let metainfo = Parser::read_torrent_file(file_path).unwrap();
+        let decoded_value = Parser::parse_torrent_file(&metainfo).unwrap();
         assert_eq!(
             decoded_value.announce_url,
             "http://bittorrent-test-tracker.codecrafters.io/announce"
@@ -88,5 +199,108 @@ mod tests {
             decoded_value.hash,
             "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
         );
+        assert!(!decoded_value.info.is_multi_file());
+        assert!(decoded_value.announce_list.is_empty());
+    }
+
+    #[test]
+    fn parse_announce_list() {
+        let torrent = b"d13:announce-listll11:http://a/a111:http://a/a2el9:udp://b:1ee4:infod6:lengthi5e4:name1:f12:piece lengthi4e6:pieces40:0000000000000000000011111111111111111111ee";
+        let metainfo = Metainfo::from_bytes(torrent.to_vec()).unwrap();
+        let decoded_value = Parser::parse_torrent_file(&metainfo).unwrap();
+        assert_eq!(
+            decoded_value.announce_list,
+            vec![
+                vec!["http://a/a1".to_string(), "http://a/a2".to_string()],
+                vec!["udp://b:1".to_string()],
+            ]
+        );
+        // Without `announce` the first tracker of the list stands in
+        assert_eq!(decoded_value.announce_url, "http://a/a1");
+    }
+
+    #[test]
+    fn parse_multi_file_torrent() {
+        let torrent = b"d8:announce23:http://tracker/announce4:infod5:filesld6:lengthi5e4:pathl1:aeed6:lengthi7e4:pathl3:sub1:beee4:name3:dir12:piece lengthi4e6:pieces60:000000000000000000001111111111111111111122222222222222222222ee";
+        let metainfo = Metainfo::from_bytes(torrent.to_vec()).unwrap();
+        let decoded_value = Parser::parse_torrent_file(&metainfo).unwrap();
+        assert!(decoded_value.info.is_multi_file());
+        assert_eq!(decoded_value.info.length, 12);
+        assert_eq!(
+            decoded_value.info.files(),
+            vec![
+                FileInfo {
+                    length: 5,
+                    path: vec!["a".to_string()]
+                },
+                FileInfo {
+                    length: 7,
+                    path: vec!["sub".to_string(), "b".to_string()]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn info_hash_covers_the_info_dictionary_as_encoded() {
+        // Unsorted keys and an unknown field, re-encoding would sort and could drop them
+        let info =
+            b"d4:name1:f6:lengthi4e12:piece lengthi4e6:pieces20:000000000000000000006:sourcei1ee";
+        let torrent = [b"d8:announce9:udp://b:14:info".as_ref(), info, b"e"].concat();
+        let metainfo = Metainfo::from_bytes(torrent).unwrap();
+
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(metainfo.info_hash(), expected);
+        assert_eq!(
+            Parser::parse_torrent_file(&metainfo).unwrap().hash,
+            hex::encode(expected)
+        );
+    }
+
+    #[test]
+    fn rejects_torrents_without_an_info_dictionary() {
+        assert!(matches!(
+            Metainfo::from_bytes(b"d8:announce9:udp://b:1e".to_vec()),
+            Err(Error::MissingField(field)) if field == "info"
+        ));
+        assert!(matches!(
+            Metainfo::from_bytes(b"d4:infod".to_vec()),
+            Err(Error::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        let parse = |info: &str| {
+            let torrent = format!("d8:announce9:udp://b:14:info{info}e");
+            Parser::parse_torrent_file(&Metainfo::from_bytes(torrent.into_bytes()).unwrap())
+        };
+
+        assert!(
+            parse("d6:lengthi4e4:name1:f12:piece lengthi4e6:pieces20:00000000000000000000e")
+                .is_ok()
+        );
+        for info in [
+            "d6:lengthi-4e4:name1:f12:piece lengthi4e6:pieces0:e",
+            "d6:lengthi4e4:name1:f12:piece lengthi0e6:pieces20:00000000000000000000e",
+            "d6:lengthi4e4:name1:f12:piece lengthi-4e6:pieces20:00000000000000000000e",
+            "d6:lengthi4e4:name1:f12:piece lengthi4e6:pieces19:0000000000000000000e",
+            "d6:lengthi5e4:name1:f12:piece lengthi4e6:pieces20:00000000000000000000e",
+            "d5:filesld6:lengthi-1e4:pathl1:aeee4:name1:d12:piece lengthi4e6:pieces0:e",
+        ] {
+            assert!(matches!(parse(info), Err(Error::Metainfo(_))), "{info}");
+        }
+    }
+
+    #[test]
+    fn multi_file_info_serializes_without_length() {
+        let torrent = b"d8:announce9:udp://b:14:infod5:filesld6:lengthi5e4:pathl1:aeee4:name1:d12:piece lengthi4e6:pieces40:0000000000000000000011111111111111111111ee";
+        let info = Parser::parse_torrent_file(&Metainfo::from_bytes(torrent.to_vec()).unwrap())
+            .unwrap()
+            .info;
+        assert_eq!(
+            serde_bencode::to_bytes(&info).unwrap(),
+            b"d5:filesld6:lengthi5e4:pathl1:aeee4:name1:d12:piece lengthi4e6:pieces40:0000000000000000000011111111111111111111e"
+        );
     }
 }
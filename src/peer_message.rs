@@ -1,64 +1,323 @@
-use eyre::{eyre, Result};
+use crate::error::{Error, Result};
+use bytes::Bytes;
 
 pub const BLOCK_SIZE: i32 = 16 * 1024;
 
 // All the remaining messages in the protocol take the form of <length prefix><message ID><payload>
+// The length prefix is a four byte big-endian value, the message ID is a single decimal byte
+// and the payload is message dependent. A length prefix of zero is a keep-alive.
 
-#[derive(Clone, Debug)]
-pub struct Message {
-    // The length prefix is a four byte big-endian value
-    pub prefix: i32,
-
-    // The message ID is a single decimal byte
-    pub id: MESSAGE,
-
-    // The payload is message dependent.
-    pub payload: Vec<u8>,
+/// Peer Messages
+/// https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Zero-length message sent to keep an idle connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// The sender has downloaded and verified the piece
+    Have {
+        index: u32,
+    },
+    /// The pieces the sender has, the high bit of the first byte is piece 0
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        /// Shares the buffer the message was read into instead of copying the block
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The DHT port of the sender
+    Port(u16),
+    /// BEP 10 extension message, `id` 0 is the extended handshake and every other
+    /// id is the one the receiver assigned to an extension in its handshake
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
 }
 
 impl Message {
-    pub fn new(id: MESSAGE, payload: Vec<u8>) -> Self {
-        Self {
-            prefix: payload.len() as i32 + 1,
-            id,
-            payload,
+    /// The message ID, a keep-alive has none
+    pub fn id(&self) -> Option<MESSAGE> {
+        match self {
+            Message::KeepAlive => None,
+            Message::Choke => Some(MESSAGE::CHOKE),
+            Message::Unchoke => Some(MESSAGE::UNCHOKE),
+            Message::Interested => Some(MESSAGE::INTERESTED),
+            Message::NotInterested => Some(MESSAGE::NOT_INTERESTED),
+            Message::Have { .. } => Some(MESSAGE::HAVE),
+            Message::Bitfield(_) => Some(MESSAGE::BITFIELD),
+            Message::Request { .. } => Some(MESSAGE::REQUEST),
+            Message::Piece { .. } => Some(MESSAGE::PIECE),
+            Message::Cancel { .. } => Some(MESSAGE::CANCEL),
+            Message::Port(_) => Some(MESSAGE::PORT),
+            Message::Extended { .. } => Some(MESSAGE::EXTENDED),
         }
     }
 
+    /// The payload following the message ID
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => vec![],
+            Message::Have { index } => index.to_be_bytes().to_vec(),
+            Message::Bitfield(bitfield) => bitfield.clone(),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => [
+                index.to_be_bytes().as_slice(),
+                begin.to_be_bytes().as_slice(),
+                length.to_be_bytes().as_slice(),
+            ]
+            .concat(),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => [
+                index.to_be_bytes().as_slice(),
+                begin.to_be_bytes().as_slice(),
+                block.as_ref(),
+            ]
+            .concat(),
+            Message::Port(port) => port.to_be_bytes().to_vec(),
+            Message::Extended { id, payload } => [[*id].as_slice(), payload.as_ref()].concat(),
+        }
+    }
+
+    /// Encode the message including its length prefix
     pub fn to_bytes(&self) -> Vec<u8> {
+        let Some(id) = self.id() else {
+            return 0u32.to_be_bytes().to_vec();
+        };
+        let payload = self.payload();
+
         [
-            self.prefix.to_be_bytes().as_slice(),
-            [self.id.clone() as u8; 1].as_slice(),
-            self.payload.as_slice(),
+            (payload.len() as u32 + 1).to_be_bytes().as_slice(),
+            [id as u8; 1].as_slice(),
+            payload.as_slice(),
         ]
         .concat()
     }
+
+    /// Decode a message from its ID and payload, the length prefix already stripped
+    pub fn from_parts(id: u8, payload: Bytes) -> Result<Self> {
+        let id = MESSAGE::try_from(id)?;
+
+        let message = match id {
+            MESSAGE::CHOKE => Message::Choke,
+            MESSAGE::UNCHOKE => Message::Unchoke,
+            MESSAGE::INTERESTED => Message::Interested,
+            MESSAGE::NOT_INTERESTED => Message::NotInterested,
+            MESSAGE::HAVE => Message::Have {
+                index: read_u32(&payload, 0)?,
+            },
+            MESSAGE::BITFIELD => Message::Bitfield(payload.to_vec()),
+            MESSAGE::REQUEST => Message::Request {
+                index: read_u32(&payload, 0)?,
+                begin: read_u32(&payload, 4)?,
+                length: read_u32(&payload, 8)?,
+            },
+            MESSAGE::PIECE => Message::Piece {
+                index: read_u32(&payload, 0)?,
+                begin: read_u32(&payload, 4)?,
+                block: payload.slice(8..),
+            },
+            MESSAGE::CANCEL => Message::Cancel {
+                index: read_u32(&payload, 0)?,
+                begin: read_u32(&payload, 4)?,
+                length: read_u32(&payload, 8)?,
+            },
+            MESSAGE::PORT => Message::Port(u16::from_be_bytes(
+                payload
+                    .get(..2)
+                    .and_then(|port| port.try_into().ok())
+                    .ok_or_else(|| Error::protocol("PORT payload too short"))?,
+            )),
+            MESSAGE::EXTENDED => Message::Extended {
+                id: *payload
+                    .first()
+                    .ok_or_else(|| Error::protocol("EXTENDED payload is empty"))?,
+                payload: payload.slice(1..),
+            },
+        };
+
+        let expected = match id {
+            MESSAGE::CHOKE | MESSAGE::UNCHOKE | MESSAGE::INTERESTED | MESSAGE::NOT_INTERESTED => {
+                Some(0)
+            }
+            MESSAGE::HAVE => Some(4),
+            MESSAGE::REQUEST | MESSAGE::CANCEL => Some(12),
+            MESSAGE::PORT => Some(2),
+            MESSAGE::BITFIELD | MESSAGE::PIECE | MESSAGE::EXTENDED => None,
+        };
+        if let Some(expected) = expected {
+            if payload.len() != expected {
+                return Err(Error::protocol(format!(
+                    "{:?} payload has {} bytes, expected {}",
+                    id,
+                    payload.len(),
+                    expected
+                )));
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// Decode a complete message including its length prefix
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let prefix = read_u32(bytes, 0)? as usize;
+        if bytes.len() != prefix + 4 {
+            return Err(Error::protocol(format!(
+                "length prefix is {prefix}, got {} bytes",
+                bytes.len() - 4
+            )));
+        }
+        if prefix == 0 {
+            return Ok(Message::KeepAlive);
+        }
+
+        Message::from_parts(bytes[4], Bytes::copy_from_slice(&bytes[5..]))
+    }
 }
 
-/// Peer Messages
+fn read_u32(payload: &[u8], at: usize) -> Result<u32> {
+    let bytes = payload
+        .get(at..at + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::protocol(format!("payload too short: {} bytes", payload.len())))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Peer Message IDs
 /// All non-keepalive messages start with a single byte which gives their type.
 /// https://www.bittorrent.org/beps/bep_0003.html#peer-messages
-#[derive(PartialEq, Clone, Debug)]
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum MESSAGE {
-    BITFIELD = 5,
-    INTERESTED = 2,
+    CHOKE = 0,
     UNCHOKE = 1,
+    INTERESTED = 2,
+    NOT_INTERESTED = 3,
+    HAVE = 4,
+    BITFIELD = 5,
     REQUEST = 6,
     PIECE = 7,
+    CANCEL = 8,
+    PORT = 9,
+    /// https://www.bittorrent.org/beps/bep_0010.html
+    EXTENDED = 20,
 }
 
 impl TryFrom<u8> for MESSAGE {
-    type Error = eyre::Error;
+    type Error = Error;
 
     fn try_from(value: u8) -> Result<Self> {
         match value {
+            0 => Ok(MESSAGE::CHOKE),
             1 => Ok(MESSAGE::UNCHOKE),
             2 => Ok(MESSAGE::INTERESTED),
+            3 => Ok(MESSAGE::NOT_INTERESTED),
+            4 => Ok(MESSAGE::HAVE),
             5 => Ok(MESSAGE::BITFIELD),
             6 => Ok(MESSAGE::REQUEST),
             7 => Ok(MESSAGE::PIECE),
-            _ => Err(eyre!("Invalid message ID: {value}")),
+            8 => Ok(MESSAGE::CANCEL),
+            9 => Ok(MESSAGE::PORT),
+            20 => Ok(MESSAGE::EXTENDED),
+            _ => Err(Error::protocol(format!("invalid message ID: {value}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 42 },
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: Bytes::from_static(&[1, 2, 3]),
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::Extended {
+                id: 3,
+                payload: Bytes::from_static(b"d8:msg_typei0e5:piecei0ee"),
+            },
+        ];
+
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
         }
     }
+
+    #[test]
+    fn encodes_wire_format() {
+        assert_eq!(Message::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+        assert_eq!(Message::Interested.to_bytes(), [0, 0, 0, 1, 2]);
+        assert_eq!(
+            Message::Have { index: 3 }.to_bytes(),
+            [0, 0, 0, 5, 4, 0, 0, 0, 3]
+        );
+        assert_eq!(Message::Port(6881).to_bytes(), [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let parse = |id: MESSAGE, payload: &[u8]| {
+            Message::from_parts(id as u8, Bytes::copy_from_slice(payload))
+        };
+        assert!(Message::from_parts(10, Bytes::new()).is_err());
+        assert!(parse(MESSAGE::HAVE, &[0, 0, 1]).is_err());
+        assert!(parse(MESSAGE::REQUEST, &[0; 13]).is_err());
+        assert!(parse(MESSAGE::PIECE, &[0; 7]).is_err());
+        assert!(parse(MESSAGE::UNCHOKE, &[0]).is_err());
+        assert!(parse(MESSAGE::EXTENDED, &[]).is_err());
+    }
 }
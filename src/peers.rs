@@ -1,27 +1,38 @@
 use core::fmt;
 use std::{
     collections::HashMap,
-    net::{SocketAddrV4, UdpSocket},
+    net::{Ipv6Addr, SocketAddr, UdpSocket},
     str::FromStr,
+    sync::OnceLock,
     time::Duration,
 };
 
-use eyre::{eyre, Context, ContextCompat, Result};
+use eyre::{eyre, Context};
 use reqwest::Client;
 use url::form_urlencoded;
 
-use crate::{decode::Decoder, parse::Parser, TrackerRequest, TrackerResponse};
+use crate::{
+    decode::Decoder,
+    error::{Error, Result},
+    parse::{Metainfo, Parser},
+    tracker::AnnounceList,
+    udp_tracker::UdpTracker,
+    ScrapeStats, TrackerRequest, TrackerResponse,
+};
+
+/// Time an HTTP tracker gets to answer an announce or scrape
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub struct Peer(pub SocketAddrV4);
+pub struct Peer(pub SocketAddr);
 
 // Implement the FromStr trait for Peer
 impl FromStr for Peer {
     type Err = std::net::AddrParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // Parse the string into an Ipv4Addr
-        let addr = SocketAddrV4::from_str(s)?;
-        // Wrap the Ipv4Addr in a Peer and return it
+        // Parse the string into an IPv4 or bracketed IPv6 address with port
+        let addr = SocketAddr::from_str(s)?;
+        // Wrap the address in a Peer and return it
         Ok(Peer(addr))
     }
 }
@@ -34,55 +45,194 @@ impl fmt::Display for Peer {
 }
 
 impl Peer {
-    pub async fn discover_peers(
-        dictionary: &HashMap<Vec<u8>, serde_bencode::value::Value>,
-    ) -> Result<TrackerResponse> {
-        let announce = Decoder::extract_string("announce", dictionary)?;
-        let info = Decoder::extract_dict("info", dictionary)?;
-
-        // Extract the info hash into &[u8] from the dictionary
-        let info_hash_value = dictionary.get(b"info".as_ref()).context("no info")?;
-        let info_hash = Parser::get_info_hash_array(info_hash_value)?;
-
-        // Compose the tracker request object
-        let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: Decoder::extract_int("piece length", &info)? as usize,
-            compact: 1,
-        };
+    /// Announce to the trackers of the torrent, all tiers of its announce-list if it has one
+    pub async fn discover_peers(metainfo: &Metainfo) -> Result<TrackerResponse> {
+        let torrent = Parser::parse_torrent_file(metainfo)?;
+        let mut trackers = AnnounceList::from_torrent(&torrent);
 
-        let url_params =
-            serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
+        // Nothing is downloaded yet, the whole torrent is left
+        let left = torrent.info.length as usize;
+        trackers
+            .announce(&metainfo.info_hash(), &TrackerRequest::new(left))
+            .await
+            .map_err(|e| Error::tracker(torrent.announce_url, e))
+    }
 
-        if announce.starts_with("udp://") {
+    /// Send `request` to the tracker at `announce` for the torrent identified by `info_hash`
+    pub async fn announce(
+        announce: &str,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        let response = if announce.starts_with("udp://") {
             // UDP protocol
-            let response = Peer::query_udp_tracker(&announce, &request, &info_hash).await?;
-            Ok(response)
+            Peer::query_udp_tracker(announce, request, info_hash).await
         } else {
             // HTTP or HTTPS protocols
-            let response = Peer::query_http_tracker(&announce, &url_params, &info_hash).await?;
-            Ok(response)
+            Peer::query_http_tracker(announce, request, info_hash).await
         }
+        .map_err(|e| Error::tracker(announce, e))?;
+
+        if let Some(warning) = &response.warning_message {
+            tracing::warn!("Tracker {} warns: {}", announce, warning);
+        }
+        Ok(response)
     }
 
-    async fn query_http_tracker(
+    /// Ask the trackers of the torrent about its swarm, the first tracker that answers wins.
+    /// Returns that tracker's URL and what it knows.
+    pub async fn scrape_torrent(metainfo: &Metainfo) -> Result<(String, ScrapeStats)> {
+        let trackers = AnnounceList::from_torrent(&Parser::parse_torrent_file(metainfo)?);
+        let info_hash = metainfo.info_hash();
+
+        let mut last_error = Error::metainfo("no trackers to scrape");
+        for announce in trackers.tiers().iter().flatten() {
+            match Peer::scrape(announce, &[info_hash]).await {
+                Ok(stats) => {
+                    if let Some(stats) = stats.get(&info_hash) {
+                        return Ok((announce.clone(), *stats));
+                    }
+                    last_error = Error::tracker(announce, "torrent is unknown to the tracker");
+                }
+                Err(e) => last_error = e,
+            }
+            tracing::warn!("{}", last_error.chain());
+        }
+        Err(last_error)
+    }
+
+    /// Scrape the tracker at `announce` for the swarms of `info_hashes`.
+    /// Torrents the tracker does not know are missing from the result.
+    pub async fn scrape(
+        announce: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+        Peer::query_scrape(announce, info_hashes)
+            .await
+            .map_err(|e| Error::tracker(announce, e))
+    }
+
+    async fn query_scrape(
+        announce: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> eyre::Result<HashMap<[u8; 20], ScrapeStats>> {
+        if announce.starts_with("udp://") {
+            let mut tracker = UdpTracker::connect(announce).await?;
+            let stats = tracker.scrape(info_hashes).await?;
+            return Ok(info_hashes.iter().copied().zip(stats).collect());
+        }
+
+        let scrape = Peer::scrape_url(announce)?;
+        let info_hashes: Vec<String> = info_hashes
+            .iter()
+            .map(|info_hash| {
+                let encoded: String = form_urlencoded::byte_serialize(info_hash).collect();
+                format!("info_hash={encoded}")
+            })
+            .collect();
+        let separator = if scrape.contains('?') { '&' } else { '?' };
+        let scrape_url = format!("{}{}{}", scrape, separator, info_hashes.join("&"));
+
+        let response = Peer::http_client()?
+            .get(&scrape_url)
+            .send()
+            .await
+            .context("query tracker scrape")?;
+        let response_bytes = response.bytes().await.context("fetch scrape response")?;
+        Peer::parse_scrape_response(&response_bytes)
+    }
+
+    /// The scrape URL of an announce URL, by convention the last path segment
+    /// `announce` is replaced with `scrape`. Trackers whose announce URL does not
+    /// follow the convention do not support scraping.
+    pub fn scrape_url(announce: &str) -> Result<String> {
+        let (base, query) = match announce.split_once('?') {
+            Some((base, query)) => (base, Some(query)),
+            None => (announce, None),
+        };
+        let slash = base
+            .rfind('/')
+            .ok_or_else(|| Error::tracker(announce, "announce URL has no path"))?;
+        let segment = &base[slash + 1..];
+        let rest = segment
+            .strip_prefix("announce")
+            .ok_or_else(|| Error::tracker(announce, "tracker does not support scrape"))?;
+
+        let mut scrape = format!("{}scrape{}", &base[..=slash], rest);
+        if let Some(query) = query {
+            scrape.push('?');
+            scrape.push_str(query);
+        }
+        Ok(scrape)
+    }
+
+    /// A bencoded scrape response, the `files` dictionary keyed by info hash
+    fn parse_scrape_response(bytes: &[u8]) -> eyre::Result<HashMap<[u8; 20], ScrapeStats>> {
+        let response = match serde_bencode::from_bytes(bytes).context("parse scrape response")? {
+            serde_bencode::value::Value::Dict(d) => d,
+            _ => return Err(eyre!("Incorrect format, required dict")),
+        };
+        if let Ok(reason) = Decoder::extract_string("failure reason", &response) {
+            return Err(eyre!("tracker failure: {reason}"));
+        }
+
+        Decoder::extract_dict("files", &response)?
+            .iter()
+            .map(|(info_hash, file)| {
+                let info_hash: [u8; 20] = info_hash
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| eyre!("scrape response has an invalid info hash"))?;
+                let file = match file {
+                    serde_bencode::value::Value::Dict(d) => d,
+                    _ => return Err(eyre!("Incorrect format, scrape entry must be a dict")),
+                };
+                let count =
+                    |key| Decoder::extract_int(key, file).map(|count| count.max(0) as usize);
+                let stats = ScrapeStats {
+                    seeders: count("complete")?,
+                    leechers: count("incomplete")?,
+                    completed: count("downloaded")?,
+                };
+                Ok((info_hash, stats))
+            })
+            .collect()
+    }
+
+    fn http_client() -> eyre::Result<Client> {
+        Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .context("build HTTP client")
+    }
+
+    /// The announce URL with the request appended, after any query it already has
+    /// such as a private tracker's passkey
+    fn announce_url(
         announce: &str,
-        url_params: &str,
+        request: &TrackerRequest,
         info_hash: &[u8; 20],
-    ) -> Result<TrackerResponse> {
+    ) -> eyre::Result<String> {
+        let url_params =
+            serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
         // URL-encode the byte array
         let url_encoded_info_hash: String = form_urlencoded::byte_serialize(info_hash).collect();
 
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            announce, url_params, url_encoded_info_hash
-        );
+        let separator = if announce.contains('?') { '&' } else { '?' };
+        Ok(format!(
+            "{}{}{}&info_hash={}",
+            announce, separator, url_params, url_encoded_info_hash
+        ))
+    }
 
-        let client = Client::new();
-        let response = client
+    async fn query_http_tracker(
+        announce: &str,
+        request: &TrackerRequest,
+        info_hash: &[u8; 20],
+    ) -> eyre::Result<TrackerResponse> {
+        let tracker_url = Peer::announce_url(announce, request, info_hash)?;
+
+        let response = Peer::http_client()?
             .get(&tracker_url)
             .send()
             .await
@@ -90,58 +240,130 @@ impl Peer {
 
         let response_bytes = response.bytes().await.context("fetch tracker response")?;
 
-        let response: TrackerResponse =
+        let mut response: TrackerResponse =
             serde_bencode::from_bytes(&response_bytes).context("parse tracker response")?;
-
-        println!("Interval: {}", response.interval);
-        for peer in &response.peers.0 {
-            println!("{}:{}", peer.ip(), peer.port());
+        if let Some(reason) = response.failure_reason {
+            return Err(eyre!("tracker failure: {reason}"));
         }
+        response.resolve_hostnames().await;
         Ok(response)
     }
 
     async fn query_udp_tracker(
         announce: &str,
-        _request: &TrackerRequest,
-        _info_hash: &[u8; 20],
-    ) -> Result<TrackerResponse> {
-        let announce = announce.trim_start_matches("udp://");
-        let parts: Vec<&str> = announce.split(':').collect();
-        if parts.len() != 2 {
-            return Err(eyre!("Invalid UDP announce URL"));
+        request: &TrackerRequest,
+        info_hash: &[u8; 20],
+    ) -> eyre::Result<TrackerResponse> {
+        let mut tracker = UdpTracker::connect(announce).await?;
+        let response = tracker.announce(request, info_hash).await?;
+        Ok(response)
+    }
+}
+
+/// Our global IPv6 address, sent to trackers as `ipv6` so that they can hand it
+/// out to IPv6 peers while we announce over IPv4 (BEP 7)
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    static LOCAL_IPV6: OnceLock<Option<Ipv6Addr>> = OnceLock::new();
+    *LOCAL_IPV6.get_or_init(|| {
+        // Connecting a UDP socket sends nothing, it only picks the source address
+        let socket = UdpSocket::bind("[::]:0").ok()?;
+        socket.connect("[2001:4860:4860::8888]:80").ok()?;
+        match socket.local_addr().ok()?.ip() {
+            std::net::IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+            _ => None,
         }
-        let host = parts[0];
-        let port: u16 = parts[1]
-            .parse()
-            .context("Invalid port in UDP announce URL")?;
+    })
+}
 
-        tracing::info!("Host: {}, Port: {}", host, port);
+/// Not loopback, unspecified, link-local or unique local
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
+}
 
-        let socket = UdpSocket::bind("0.0.0.0:0").context("bind UDP socket")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        socket
-            .set_read_timeout(Some(Duration::from_secs(5)))
-            .context("set read timeout")?;
+    #[test]
+    fn parses_ipv6_peers() {
+        let peer: Peer = "[2001:db8::1]:6881".parse().unwrap();
+        assert!(peer.0.is_ipv6());
+        assert_eq!(peer.to_string(), "[2001:db8::1]:6881");
+        assert!("127.0.0.1:6881".parse::<Peer>().unwrap().0.is_ipv4());
 
-        // Implement the UDP tracker protocol here
-        // 1. Send connection request
-        // 2. Receive connection response
-        // 3. Send announce request
-        // 4. Receive announce response
+        assert!(is_global_ipv6(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fd00::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"::1".parse().unwrap()));
+    }
 
-        // Example: Send a connection request
-        let connection_request = [0u8; 16]; // Placeholder for actual connection request
-        socket
-            .send_to(&connection_request, (host, port))
-            .context("send connection request")?;
+    #[test]
+    fn appends_the_request_to_announce_urls() {
+        let request = TrackerRequest::new(1);
+        let url = Peer::announce_url("http://example.com/announce", &request, &[0xff; 20]);
+        assert!(url.unwrap().starts_with("http://example.com/announce?"));
 
-        let mut buf = [0u8; 1024];
-        let (_amt, _src) = socket
-            .recv_from(&mut buf)
-            .context("receive connection response")?;
+        // A passkey in the query of the announce URL is kept
+        let url = Peer::announce_url(
+            "http://example.com/announce?passkey=abcd",
+            &request,
+            &[0xff; 20],
+        )
+        .unwrap();
+        assert!(url.starts_with("http://example.com/announce?passkey=abcd&"));
+        assert_eq!(url.matches('?').count(), 1);
+        assert!(url.ends_with(&format!("&info_hash={}", "%FF".repeat(20))));
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        for (announce, scrape) in [
+            ("http://example.com/announce", "http://example.com/scrape"),
+            (
+                "http://example.com/x/announce",
+                "http://example.com/x/scrape",
+            ),
+            (
+                "http://example.com/announce.php",
+                "http://example.com/scrape.php",
+            ),
+            (
+                "http://example.com/announce?passkey=ab/cd",
+                "http://example.com/scrape?passkey=ab/cd",
+            ),
+        ] {
+            assert_eq!(Peer::scrape_url(announce).unwrap(), scrape);
+        }
+
+        for announce in [
+            "http://example.com/a",
+            "http://example.com/announce/x",
+            "http://example.com/my-announce",
+        ] {
+            assert!(Peer::scrape_url(announce).is_err());
+        }
+    }
 
-        // Parse the connection response and proceed with announce request
+    #[test]
+    fn parses_scrape_responses() {
+        let mut response = b"d5:filesd20:".to_vec();
+        response.extend([7u8; 20]);
+        response.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let stats = Peer::parse_scrape_response(&response).unwrap();
+        assert_eq!(
+            stats[&[7u8; 20]],
+            ScrapeStats {
+                seeders: 5,
+                leechers: 10,
+                completed: 50
+            }
+        );
 
-        todo!()
+        assert!(Peer::parse_scrape_response(b"d14:failure reason4:nopee").is_err());
     }
 }
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::Peers;
+
+/// Name of the extension in the extended handshake
+pub const UT_PEX: &str = "ut_pex";
+
+/// PEX messages are sent at most once a minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Added and dropped peers per message are each capped at 50
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Flags sent along with every added peer
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_CONNECTABLE: u8 = 0x10;
+
+/// A ut_pex message, the peers the sender connected to and dropped since its last one
+/// https://www.bittorrent.org/beps/bep_0011.html
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PexMessage {
+    /// Address and flags of each added peer
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+/// The bencoded dictionary, IPv4 and IPv6 peers in separate compact strings
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexDict {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut dict = PexDict::default();
+
+        for (addr, flags) in &self.added {
+            match addr {
+                SocketAddr::V4(_) => dict.added_flags.push(*flags),
+                SocketAddr::V6(_) => dict.added6_flags.push(*flags),
+            }
+        }
+        let added = Peers(self.added.iter().map(|(addr, _)| *addr).collect());
+        dict.added = ByteBuf::from(added.to_compact());
+        dict.added6 = ByteBuf::from(added.to_compact6());
+        let dropped = Peers(self.dropped.clone());
+        dict.dropped = ByteBuf::from(dropped.to_compact());
+        dict.dropped6 = ByteBuf::from(dropped.to_compact6());
+
+        serde_bencode::to_bytes(&dict).context("encode ut_pex message")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let dict: PexDict = serde_bencode::from_bytes(bytes).context("parse ut_pex message")?;
+
+        let added = Peers::from_compact(&dict.added)?.0;
+        let added6 = Peers::from_compact6(&dict.added6)?.0;
+        // Flags are optional, peers without them get none
+        let flags = (0..dict.added.len() / 6)
+            .map(|i| dict.added_flags.get(i).copied().unwrap_or(0))
+            .chain(
+                (0..dict.added6.len() / 18).map(|i| dict.added6_flags.get(i).copied().unwrap_or(0)),
+            );
+
+        let dropped = Peers::from_compact(&dict.dropped)?.0;
+        let dropped6 = Peers::from_compact6(&dict.dropped6)?.0;
+
+        Ok(PexMessage {
+            added: added.into_iter().chain(added6).zip(flags).collect(),
+            dropped: dropped.into_iter().chain(dropped6).collect(),
+        })
+    }
+}
+
+/// What has been told to one peer, to send it only the changes
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message to send if a minute passed since the last one and the set of
+    /// `connected` peers changed since then
+    pub fn update(&mut self, connected: &HashSet<SocketAddr>, now: Instant) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .difference(&self.sent)
+            .take(MAX_PEX_PEERS)
+            .map(|&addr| (addr, FLAG_CONNECTABLE))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(connected)
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+
+        let message = PexMessage { added, dropped };
+        if message.is_empty() {
+            return None;
+        }
+
+        for (addr, _) in &message.added {
+            self.sent.insert(*addr);
+        }
+        for addr in &message.dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pex_message_round_trips() {
+        let message = PexMessage {
+            added: vec![
+                (
+                    "10.0.0.1:6881".parse().unwrap(),
+                    FLAG_SEED | FLAG_CONNECTABLE,
+                ),
+                ("10.0.0.2:51413".parse().unwrap(), 0),
+                ("[2001:db8::1]:6881".parse().unwrap(), FLAG_UTP),
+            ],
+            dropped: vec![
+                "10.0.0.3:6881".parse().unwrap(),
+                "[2001:db8::2]:6882".parse().unwrap(),
+            ],
+        };
+
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(PexMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn parses_messages_without_flags() {
+        // Only `added`, with the compact IPv4 peer 127.0.0.1:6881
+        let message = PexMessage::from_bytes(b"d5:added6:\x7f\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(message.added, vec![("127.0.0.1:6881".parse().unwrap(), 0)]);
+        assert!(message.dropped.is_empty());
+        assert!(PexMessage::from_bytes(b"d5:added5:12345e").is_err());
+    }
+
+    #[test]
+    fn sends_changes_at_most_once_a_minute() {
+        let mut state = PexState::new();
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        let start = Instant::now();
+
+        let first = state.update(&HashSet::from([a, b]), start).unwrap();
+        assert_eq!(first.added.len(), 2);
+        assert!(first.dropped.is_empty());
+
+        // Too early, and later without any change there is nothing to send
+        assert_eq!(state.update(&HashSet::from([a]), start), None);
+        assert_eq!(
+            state.update(&HashSet::from([a, b]), start + PEX_INTERVAL),
+            None
+        );
+
+        let second = state
+            .update(&HashSet::from([a]), start + PEX_INTERVAL * 2)
+            .unwrap();
+        assert!(second.added.is_empty());
+        assert_eq!(second.dropped, vec![b]);
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use rand::seq::IteratorRandom;
+
+/// Decides which piece a peer downloads next.
+/// The swarm shares one picker between all of its peers and reports what every
+/// peer has from their BITFIELD and HAVE messages.
+pub trait PiecePicker: Send {
+    /// A peer connected and announced the pieces it has
+    fn peer_joined(&mut self, pieces: &HashSet<usize>);
+
+    /// A connected peer announced a new piece with a HAVE message
+    fn peer_has(&mut self, piece: usize);
+
+    /// A peer disconnected, its pieces are no longer available from it
+    fn peer_left(&mut self, pieces: &HashSet<usize>);
+
+    /// Pick the next piece for a peer that has `available`
+    fn pick(&mut self, available: &HashSet<usize>) -> Option<usize>;
+
+    /// Mark a piece as verified. Returns false if another peer completed it first.
+    fn complete(&mut self, piece: usize) -> bool;
+
+    /// A peer gave up on a piece, hand it out again
+    fn abort(&mut self, piece: usize);
+
+    fn is_complete(&self, piece: usize) -> bool;
+
+    /// Whether a peer with `available` may still get work, now or once other peers give up
+    fn has_work_for(&self, available: &HashSet<usize>) -> bool;
+}
+
+/// Hands out pieces in index order, one peer per piece
+pub struct SequentialPicker {
+    pending: VecDeque<usize>,
+    in_flight: HashSet<usize>,
+    completed: HashSet<usize>,
+}
+
+impl SequentialPicker {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            pending: (0..num_pieces).collect(),
+            in_flight: HashSet::new(),
+            completed: HashSet::new(),
+        }
+    }
+}
+
+impl PiecePicker for SequentialPicker {
+    fn peer_joined(&mut self, _pieces: &HashSet<usize>) {}
+
+    fn peer_has(&mut self, _piece: usize) {}
+
+    fn peer_left(&mut self, _pieces: &HashSet<usize>) {}
+
+    fn pick(&mut self, available: &HashSet<usize>) -> Option<usize> {
+        let position = self
+            .pending
+            .iter()
+            .position(|piece| available.contains(piece))?;
+        let piece = self.pending.remove(position)?;
+        self.in_flight.insert(piece);
+        Some(piece)
+    }
+
+    fn complete(&mut self, piece: usize) -> bool {
+        // Pieces found on disk are completed without ever being picked
+        self.pending.retain(|&pending| pending != piece);
+        self.in_flight.remove(&piece);
+        self.completed.insert(piece)
+    }
+
+    fn abort(&mut self, piece: usize) {
+        if self.in_flight.remove(&piece) {
+            self.pending.push_front(piece);
+        }
+    }
+
+    fn is_complete(&self, piece: usize) -> bool {
+        self.completed.contains(&piece)
+    }
+
+    fn has_work_for(&self, available: &HashSet<usize>) -> bool {
+        self.pending
+            .iter()
+            .chain(self.in_flight.iter())
+            .any(|piece| available.contains(piece))
+    }
+}
+
+/// Picks the piece held by the fewest connected peers.
+/// Until the first piece completes pieces are chosen at random so that there is
+/// something to share quickly. Once every remaining piece is being downloaded the
+/// picker enters endgame mode and hands out in-flight pieces to idle peers too. The
+/// swarm shares blocks between the peers on a piece, so each block is requested from
+/// all of them and cancelled at the others once one delivers it.
+pub struct RarestFirstPicker {
+    availability: Vec<usize>,
+    pending: BTreeSet<usize>,
+    /// Number of peers downloading each in-flight piece
+    in_flight: HashMap<usize, usize>,
+    completed: Vec<bool>,
+    num_completed: usize,
+}
+
+impl RarestFirstPicker {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            availability: vec![0; num_pieces],
+            pending: (0..num_pieces).collect(),
+            in_flight: HashMap::new(),
+            completed: vec![false; num_pieces],
+            num_completed: 0,
+        }
+    }
+
+    /// Endgame starts when no piece is left that nobody is downloading
+    pub fn in_endgame(&self) -> bool {
+        self.pending.is_empty() && !self.in_flight.is_empty()
+    }
+
+    fn start(&mut self, piece: usize) -> usize {
+        self.pending.remove(&piece);
+        *self.in_flight.entry(piece).or_insert(0) += 1;
+        piece
+    }
+}
+
+impl PiecePicker for RarestFirstPicker {
+    fn peer_joined(&mut self, pieces: &HashSet<usize>) {
+        for &piece in pieces {
+            self.peer_has(piece);
+        }
+    }
+
+    fn peer_has(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    fn peer_left(&mut self, pieces: &HashSet<usize>) {
+        for &piece in pieces {
+            if let Some(count) = self.availability.get_mut(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    fn pick(&mut self, available: &HashSet<usize>) -> Option<usize> {
+        let mut rng = rand::thread_rng();
+        let candidates = self
+            .pending
+            .iter()
+            .copied()
+            .filter(|piece| available.contains(piece));
+
+        if self.num_completed == 0 {
+            if let Some(piece) = candidates.choose(&mut rng) {
+                return Some(self.start(piece));
+            }
+        } else if let Some(rarest) = candidates.clone().map(|p| self.availability[p]).min() {
+            // Break ties at random so that peers don't all go for the same piece
+            let piece = candidates
+                .filter(|&piece| self.availability[piece] == rarest)
+                .choose(&mut rng)?;
+            return Some(self.start(piece));
+        }
+
+        if !self.in_endgame() {
+            return None;
+        }
+
+        // Endgame: duplicate the in-flight piece with the fewest peers on it
+        let piece = self
+            .in_flight
+            .iter()
+            .filter(|(piece, _)| available.contains(piece))
+            .min_by_key(|(_, &peers)| peers)
+            .map(|(&piece, _)| piece)?;
+        tracing::debug!("Endgame: requesting piece {} from another peer", piece);
+        Some(self.start(piece))
+    }
+
+    fn complete(&mut self, piece: usize) -> bool {
+        // Pieces found on disk are completed without ever being picked
+        self.pending.remove(&piece);
+        self.in_flight.remove(&piece);
+        match self.completed.get_mut(piece) {
+            Some(completed) if !*completed => {
+                *completed = true;
+                self.num_completed += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn abort(&mut self, piece: usize) {
+        let Some(peers) = self.in_flight.get_mut(&piece) else {
+            return;
+        };
+        *peers -= 1;
+        if *peers == 0 {
+            self.in_flight.remove(&piece);
+            if !self.is_complete(piece) {
+                self.pending.insert(piece);
+            }
+        }
+    }
+
+    fn is_complete(&self, piece: usize) -> bool {
+        self.completed.get(piece).copied().unwrap_or(false)
+    }
+
+    fn has_work_for(&self, available: &HashSet<usize>) -> bool {
+        self.pending
+            .iter()
+            .chain(self.in_flight.keys())
+            .any(|piece| available.contains(piece))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_picker_hands_out_pieces_of_dropped_peers_again() {
+        let mut picker = SequentialPicker::new(3);
+        let first_peer = HashSet::from([0, 1]);
+        let second_peer = HashSet::from([1, 2]);
+
+        assert_eq!(picker.pick(&first_peer), Some(0));
+        assert_eq!(picker.pick(&second_peer), Some(1));
+        assert_eq!(picker.pick(&second_peer), Some(2));
+
+        // The first peer still waits for piece 1 in case the second peer drops it
+        assert_eq!(picker.pick(&first_peer), None);
+        assert!(picker.has_work_for(&first_peer));
+
+        picker.abort(1);
+        assert_eq!(picker.pick(&first_peer), Some(1));
+
+        assert!(picker.complete(0));
+        assert!(picker.complete(1));
+        assert!(picker.complete(2));
+        assert!(!picker.has_work_for(&first_peer));
+    }
+
+    #[test]
+    fn picks_rarest_piece_after_the_first_completes() {
+        let mut picker = RarestFirstPicker::new(4);
+        let everything = HashSet::from([0, 1, 2, 3]);
+        picker.peer_joined(&everything);
+        picker.peer_joined(&HashSet::from([0, 1, 3]));
+        picker.peer_joined(&HashSet::from([0, 3]));
+        for piece in [1, 1] {
+            picker.peer_has(piece);
+        }
+
+        // Availability is now 0: 3, 1: 4, 2: 1, 3: 3
+        assert!(picker.complete(1));
+        assert_eq!(picker.pick(&everything), Some(2));
+        assert_eq!(picker.pick(&HashSet::from([0, 1])), Some(0));
+
+        // Availability drops when peers leave
+        picker.peer_left(&everything);
+        assert_eq!(picker.availability[2], 0);
+    }
+
+    #[test]
+    fn endgame_duplicates_in_flight_pieces() {
+        let mut picker = RarestFirstPicker::new(2);
+        let peer = HashSet::from([0, 1]);
+        picker.peer_joined(&peer);
+        picker.peer_joined(&peer);
+
+        let first = picker.pick(&peer).unwrap();
+        let second = picker.pick(&peer).unwrap();
+        assert_ne!(first, second);
+        assert!(picker.in_endgame());
+
+        // An idle peer gets one of the pieces already being downloaded
+        let duplicate = picker.pick(&peer).unwrap();
+        assert!(duplicate == first || duplicate == second);
+
+        // The first copy wins, the second is discarded
+        assert!(picker.complete(duplicate));
+        assert!(picker.is_complete(duplicate));
+        assert!(!picker.complete(duplicate));
+        picker.abort(duplicate);
+        assert!(picker.has_work_for(&peer));
+    }
+}
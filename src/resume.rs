@@ -0,0 +1,241 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    files::FileLayout,
+    storage::{blocking, Storage},
+    Info, Peers,
+};
+
+/// Most peers remembered for the next run
+pub const MAX_RESUME_PEERS: usize = 200;
+
+/// Size and modification time of a file when the resume data was saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub length: u64,
+    /// Nanoseconds since the Unix epoch
+    pub mtime: i64,
+}
+
+/// What a download had on disk, saved next to it so that a restarted download only
+/// fetches the missing pieces. Resume data is only trusted while every file still
+/// has the size and modification time recorded, otherwise the data is rechecked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash")]
+    pub info_hash: ByteBuf,
+    /// Verified pieces, high bit of the first byte is piece 0
+    pub pieces: ByteBuf,
+    pub files: Vec<ResumeFile>,
+    /// Peers of the last run in compact form
+    #[serde(default)]
+    pub peers: ByteBuf,
+    #[serde(default)]
+    pub peers6: ByteBuf,
+}
+
+impl ResumeData {
+    /// Where the resume data of a download to `output_path` is kept
+    pub fn path_for<T>(output_path: T) -> PathBuf
+    where
+        T: AsRef<Path>,
+    {
+        let mut path = output_path.as_ref().as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// The state of the files in `layout` right now, with `have` as verified pieces
+    pub fn capture(
+        layout: &FileLayout,
+        info_hash: [u8; 20],
+        have: &[bool],
+        peers: &[SocketAddr],
+    ) -> Result<Self> {
+        let mut pieces = vec![0u8; have.len().div_ceil(8)];
+        for (index, _) in have.iter().enumerate().filter(|(_, &have)| have) {
+            pieces[index / 8] |= 0x80 >> (index % 8);
+        }
+        let peers = Peers(peers.iter().take(MAX_RESUME_PEERS).copied().collect());
+
+        Ok(ResumeData {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            pieces: ByteBuf::from(pieces),
+            files: ResumeData::file_states(layout)?,
+            peers: ByteBuf::from(peers.to_compact()),
+            peers6: ByteBuf::from(peers.to_compact6()),
+        })
+    }
+
+    /// Resume data saved earlier, `None` if there is none
+    pub fn load<T>(path: T) -> Result<Option<Self>>
+    where
+        T: AsRef<Path>,
+    {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read resume data"),
+        };
+        serde_bencode::from_bytes(&bytes)
+            .map(Some)
+            .context("parse resume data")
+    }
+
+    /// Write to a temporary file first so that a crash never leaves half a resume file
+    pub fn save<T>(&self, path: T) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, serde_bencode::to_bytes(self)?).context("save resume data")?;
+        std::fs::rename(&temporary, path).context("save resume data")
+    }
+
+    /// Whether the data belongs to this torrent and no file changed since it was saved
+    pub fn matches(&self, layout: &FileLayout, info_hash: [u8; 20], num_pieces: usize) -> bool {
+        self.info_hash.as_slice() == info_hash
+            && self.pieces.len() == num_pieces.div_ceil(8)
+            && ResumeData::file_states(layout).is_ok_and(|files| files == self.files)
+    }
+
+    /// The verified pieces as recorded
+    pub fn have(&self, num_pieces: usize) -> Vec<bool> {
+        (0..num_pieces)
+            .map(|index| {
+                self.pieces
+                    .get(index / 8)
+                    .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+            })
+            .collect()
+    }
+
+    /// Peers of the last run, worth trying again
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = Peers::from_compact(&self.peers).unwrap_or_default().0;
+        peers.extend(Peers::from_compact6(&self.peers6).unwrap_or_default().0);
+        peers
+    }
+
+    fn file_states(layout: &FileLayout) -> Result<Vec<ResumeFile>> {
+        layout
+            .files
+            .iter()
+            .map(|entry| {
+                let metadata = std::fs::metadata(&entry.path)
+                    .with_context(|| format!("stat {}", entry.path.display()))?;
+                let mtime = metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| eyre!("{} modified before 1970", entry.path.display()))?;
+                Ok(ResumeFile {
+                    length: metadata.len(),
+                    mtime: mtime.as_nanos() as i64,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Hash every piece in `storage` against `info.pieces`.
+/// Pieces that cannot be read, e.g. because a file is missing, count as not verified.
+pub async fn recheck(storage: &Arc<dyn Storage>, info: &Info) -> Vec<bool> {
+    let num_pieces = info.num_pieces();
+    let hashes: Vec<[u8; 20]> = info
+        .pieces
+        .chunks(20)
+        .map(|hash| <[u8; 20]>::try_from(hash).unwrap_or_default())
+        .collect();
+
+    blocking(storage, move |storage| {
+        Ok(hashes
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| storage.verify_piece(index, hash).unwrap_or(false))
+            .collect())
+    })
+    .await
+    .unwrap_or_else(|_| vec![false; num_pieces])
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn info_of(data: &[u8], piece_length: usize) -> Info {
+        Info {
+            name: "data.bin".to_string(),
+            length: data.len() as i64,
+            files: None,
+            piece_length: piece_length as i64,
+            pieces: data
+                .chunks(piece_length)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn recheck_finds_verified_pieces() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let info = info_of(&data, 4096);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let layout = FileLayout::new(&path, &info).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(FileStorage::new(layout, 4096));
+
+        assert_eq!(recheck(&storage, &info).await, vec![false; 3]);
+
+        // The middle piece is corrupt, the last one is short
+        let mut corrupt = data.clone();
+        corrupt[5000] ^= 0xff;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(recheck(&storage, &info).await, vec![true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn resume_data_is_trusted_until_files_change() {
+        let data = vec![7u8; 8192];
+        let info = info_of(&data, 4096);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+        let layout = FileLayout::new(&path, &info).unwrap();
+
+        let peers: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        ];
+        let resume = ResumeData::capture(&layout, [1; 20], &[true, false], &peers).unwrap();
+        let resume_path = ResumeData::path_for(&path);
+        assert_eq!(resume_path, dir.path().join("data.bin.resume"));
+        resume.save(&resume_path).unwrap();
+
+        let loaded = ResumeData::load(&resume_path).unwrap().unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.matches(&layout, [1; 20], 2));
+        assert!(!loaded.matches(&layout, [2; 20], 2));
+        assert_eq!(loaded.have(2), vec![true, false]);
+        assert_eq!(loaded.peers(), peers);
+
+        std::fs::write(&path, vec![8u8; 100]).unwrap();
+        assert!(!loaded.matches(&layout, [1; 20], 2));
+
+        assert_eq!(ResumeData::load(dir.path().join("missing")).unwrap(), None);
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use eyre::{eyre, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::interval,
+};
+
+use crate::{
+    choker::{Choker, PeerStats, RECHOKE_INTERVAL},
+    codec::{PeerCodec, PeerStream},
+    extension::{Extended, ExtensionRegistry},
+    files::FileLayout,
+    handshake::Handshake,
+    metadata::{MetadataMessage, UT_METADATA},
+    peer_message::Message,
+    resume::recheck,
+    storage::{blocking, Storage, StorageKind},
+    tracker::TorrentStats,
+    TorrentResponse,
+};
+
+/// The port announced to trackers in `TrackerRequest`
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// Number of peers uploaded to at the same time
+pub const UPLOAD_SLOTS: usize = 4;
+
+/// Largest block a peer may request, clients use 16 KiB and nobody goes beyond 128 KiB
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// Idle connections get a keep-alive so that the peer does not drop them
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// A torrent whose data is on disk and can be served
+struct SeededTorrent {
+    torrent: TorrentResponse,
+    storage: Arc<dyn Storage>,
+    bitfield: Vec<u8>,
+    /// The bencoded info dictionary served over ut_metadata
+    metadata: Option<Vec<u8>>,
+    stats: Arc<TorrentStats>,
+}
+
+impl SeededTorrent {
+    fn has_piece(&self, index: usize) -> bool {
+        self.bitfield
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+}
+
+/// Connected peers and the choker deciding which of them are uploaded to
+struct Connections {
+    /// Choke and unchoke messages are sent through the connection task of the peer
+    commands: HashMap<SocketAddr, mpsc::UnboundedSender<Message>>,
+    choker: Choker<SocketAddr>,
+}
+
+impl Connections {
+    fn send(&self, addr: &SocketAddr, message: Message) {
+        if let Some(commands) = self.commands.get(addr) {
+            let _ = commands.send(message);
+        }
+    }
+}
+
+/// Serves pieces of complete torrents to inbound peers
+pub struct Seeder {
+    torrents: HashMap<[u8; 20], Arc<SeededTorrent>>,
+    connections: Mutex<Connections>,
+    storage: StorageKind,
+}
+
+impl Default for Seeder {
+    fn default() -> Self {
+        Self {
+            torrents: HashMap::new(),
+            connections: Mutex::new(Connections {
+                commands: HashMap::new(),
+                choker: Choker::new(true).with_regular_slots(UPLOAD_SLOTS),
+            }),
+            storage: StorageKind::default(),
+        }
+    }
+}
+
+impl Seeder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of regular unchoke slots, one more peer is unchoked optimistically
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        let connections = self.connections.get_mut().unwrap();
+        connections.choker = Choker::new(true).with_regular_slots(upload_slots);
+        self
+    }
+
+    /// How torrents added from now on read their data
+    pub fn with_storage(mut self, storage: StorageKind) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Seed a torrent from the data at `data_path`.
+    /// Every piece is hashed first, only the pieces that verify are offered to peers.
+    pub async fn add_torrent<T>(&mut self, torrent: TorrentResponse, data_path: T) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        let layout = FileLayout::new(data_path, &torrent.info)?;
+        let storage = self.storage.open(layout, &torrent.info);
+        let num_pieces = torrent.info.num_pieces();
+        let mut bitfield = vec![0u8; num_pieces.div_ceil(8)];
+        let mut verified = 0;
+        let mut left = 0;
+
+        for (index, have) in recheck(&storage, &torrent.info)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            if have {
+                bitfield[index / 8] |= 0x80 >> (index % 8);
+                verified += 1;
+            } else {
+                left += torrent.info.piece_size(index) as u64;
+            }
+        }
+
+        if verified < num_pieces {
+            tracing::warn!(
+                "Only {}/{} pieces of {} verified",
+                verified,
+                num_pieces,
+                torrent.info.name
+            );
+        }
+
+        // The info dictionary is served as the torrent encoded it, a torrent built
+        // by hand may come without it
+        let info_hash = torrent.info_hash()?;
+        let metadata = (<[u8; 20]>::from(Sha1::digest(&torrent.raw_info)) == info_hash)
+            .then(|| torrent.raw_info.clone());
+
+        self.torrents.insert(
+            info_hash,
+            Arc::new(SeededTorrent {
+                torrent,
+                storage,
+                bitfield,
+                metadata,
+                stats: TorrentStats::new(left),
+            }),
+        );
+
+        Ok(())
+    }
+
+    /// Total bytes uploaded for a torrent
+    pub fn uploaded(&self, info_hash: &[u8; 20]) -> u64 {
+        self.torrents
+            .get(info_hash)
+            .map_or(0, |seeded| seeded.stats.uploaded())
+    }
+
+    /// Transfer totals of a torrent, for announcing it to its trackers
+    pub fn stats(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentStats>> {
+        self.torrents
+            .get(info_hash)
+            .map(|seeded| seeded.stats.clone())
+    }
+
+    /// Accept peers on `listener` until it fails, rechoking them every 10 seconds
+    pub async fn run(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        tracing::info!("Seeding on {}", listener.local_addr()?);
+        let mut rechoke = interval(RECHOKE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted.context("accept peer")?;
+                    let seeder = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = seeder.serve_peer(stream, addr).await {
+                            tracing::debug!("Peer {} disconnected: {:#}", addr, e);
+                        }
+                        seeder.peer_left(addr);
+                    });
+                }
+                _ = rechoke.tick() => self.rechoke(),
+            }
+        }
+    }
+
+    async fn serve_peer(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let handshake = Handshake::accept(&mut stream, |info_hash| {
+            self.torrents.contains_key(info_hash)
+        })
+        .await?;
+        let seeded = self
+            .torrents
+            .get(&handshake.info_hash)
+            .ok_or(eyre!("unknown torrent"))?;
+        tracing::info!("Peer {} connected for {}", addr, seeded.torrent.info.name);
+
+        let mut peer = PeerCodec::framed(stream);
+        // The bitfield has to be the first message, the extended handshake follows
+        peer.send(Message::Bitfield(seeded.bitfield.clone()))
+            .await?;
+        let mut extensions = ExtensionRegistry::new();
+        if handshake.supports_extensions() {
+            if seeded.metadata.is_some() {
+                extensions.register(UT_METADATA);
+            }
+            let mut extended = extensions.handshake();
+            extended.metadata_size = seeded.metadata.as_ref().map(|m| m.len() as i64);
+            extended.set_your_ip(addr.ip());
+            peer.send(ExtensionRegistry::handshake_message(&extended)?)
+                .await?;
+        }
+
+        let stats = PeerStats::new();
+        let (commands, mut choking) = mpsc::unbounded_channel();
+        {
+            let mut connections = self.connections.lock().unwrap();
+            connections.commands.insert(addr, commands);
+            connections.choker.add_peer(addr, stats.clone());
+        }
+
+        let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+        keep_alive.tick().await;
+
+        loop {
+            tokio::select! {
+                message = peer.next() => {
+                    let Some(message) = message else {
+                        return Ok(());
+                    };
+                    let message = message?;
+                    if let Message::Extended { id, payload } = message {
+                        Seeder::handle_extended(&mut peer, seeded, &mut extensions, id, payload)
+                            .await?;
+                    } else {
+                        self.handle_message(&mut peer, seeded, &stats, addr, message).await?;
+                    }
+                }
+                Some(command) = choking.recv() => peer.send(command).await?,
+                _ = keep_alive.tick() => peer.send(Message::KeepAlive).await?,
+            }
+        }
+    }
+
+    async fn handle_message(
+        &self,
+        peer: &mut PeerStream,
+        seeded: &SeededTorrent,
+        stats: &PeerStats,
+        addr: SocketAddr,
+        message: Message,
+    ) -> Result<()> {
+        match message {
+            Message::Interested => self.set_interested(addr, true),
+            Message::NotInterested => self.set_interested(addr, false),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if !self.is_unchoked(addr) {
+                    tracing::debug!("Ignoring request from choked peer {}", addr);
+                    return Ok(());
+                }
+                let block = Seeder::read_block(seeded, index, begin, length).await?;
+                peer.send(Message::Piece {
+                    index,
+                    begin,
+                    block,
+                })
+                .await?;
+                seeded.stats.record_upload(length as u64);
+                stats.record_upload(length as u64);
+            }
+            // Requests are answered right away, there is nothing queued to cancel
+            Message::Cancel { .. } => {}
+            message => tracing::debug!("Ignoring {:?} from {}", message.id(), addr),
+        }
+
+        Ok(())
+    }
+
+    async fn handle_extended(
+        peer: &mut PeerStream,
+        seeded: &SeededTorrent,
+        extensions: &mut ExtensionRegistry,
+        id: u8,
+        payload: Bytes,
+    ) -> Result<()> {
+        match extensions.receive(id, payload)? {
+            Extended::Handshake(handshake) => {
+                tracing::debug!("Peer runs {:?}", handshake.v);
+            }
+            Extended::Message {
+                name: UT_METADATA,
+                payload,
+            } => {
+                let MetadataMessage::Request { piece } = MetadataMessage::from_bytes(payload)?
+                else {
+                    return Ok(());
+                };
+                let response = match &seeded.metadata {
+                    Some(metadata) => MetadataMessage::respond(metadata, piece),
+                    None => MetadataMessage::Reject { piece },
+                };
+                let payload = Bytes::from(response.to_bytes()?);
+                peer.send(extensions.message(UT_METADATA, payload)?).await?;
+            }
+            Extended::Message { name, .. } => tracing::debug!("Ignoring {} message", name),
+        }
+
+        Ok(())
+    }
+
+    async fn read_block(
+        seeded: &SeededTorrent,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<Bytes> {
+        let info = &seeded.torrent.info;
+        let index = index as usize;
+        if index >= info.num_pieces() || !seeded.has_piece(index) {
+            return Err(eyre!("request for missing piece {index}"));
+        }
+        if length == 0
+            || length > MAX_REQUEST_LENGTH
+            || begin as i64 + length as i64 > info.piece_size(index)
+        {
+            return Err(eyre!("invalid request of {length} bytes at {begin}"));
+        }
+
+        let block = blocking(&seeded.storage, move |storage| {
+            storage.read_block(index, begin, length as usize)
+        })
+        .await?;
+        Ok(Bytes::from(block))
+    }
+
+    fn is_unchoked(&self, addr: SocketAddr) -> bool {
+        self.connections.lock().unwrap().choker.is_unchoked(&addr)
+    }
+
+    /// A newly interested peer is unchoked right away while a slot is free,
+    /// everything else waits for the next choker round
+    fn set_interested(&self, addr: SocketAddr, interested: bool) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.choker.set_interested(&addr, interested) {
+            connections.send(&addr, Message::Unchoke);
+        }
+    }
+
+    fn peer_left(&self, addr: SocketAddr) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.commands.remove(&addr);
+        connections.choker.remove_peer(&addr);
+    }
+
+    fn rechoke(&self) {
+        let mut connections = self.connections.lock().unwrap();
+        let update = connections.choker.recompute(Instant::now());
+        for addr in &update.choke {
+            connections.send(addr, Message::Choke);
+        }
+        for addr in &update.unchoke {
+            connections.send(addr, Message::Unchoke);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata::Metadata,
+        parse::{Metainfo, Parser},
+        peers::Peer,
+        swarm::tests::Fixture,
+    };
+
+    #[tokio::test]
+    async fn serves_metadata_over_extension_protocol() {
+        // Enough pieces for the info dictionary to span two ut_metadata pieces
+        let fixture = Fixture::new(1000 * 1024, 1024);
+        let info_hash = fixture.torrent.info_hash().unwrap();
+        let addr = fixture.seed().await;
+
+        let metadata = Metadata::fetch(info_hash, Peer(addr)).await.unwrap();
+        assert!(metadata.len() > 16 * 1024);
+        assert_eq!(<[u8; 20]>::from(Sha1::digest(&metadata)), info_hash);
+    }
+
+    #[tokio::test]
+    async fn serves_info_dictionaries_with_unknown_keys() {
+        let mut fixture = Fixture::new(40_000, 16 * 1024);
+        // Keys out of order and one we don't parse, both part of the info hash
+        let mut info =
+            b"d4:name8:data.bin6:lengthi40000e12:piece lengthi16384e6:pieces60:".to_vec();
+        info.extend_from_slice(&fixture.torrent.info.pieces);
+        info.extend_from_slice(b"6:source7:privatee");
+        let mut torrent = b"d8:announce0:4:info".to_vec();
+        torrent.extend_from_slice(&info);
+        torrent.push(b'e');
+        fixture.torrent =
+            Parser::parse_torrent_file(&Metainfo::from_bytes(torrent).unwrap()).unwrap();
+
+        let info_hash = fixture.torrent.info_hash().unwrap();
+        let addr = fixture.seed().await;
+        assert_eq!(Metadata::fetch(info_hash, Peer(addr)).await.unwrap(), info);
+    }
+}
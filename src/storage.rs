@@ -0,0 +1,607 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use eyre::{eyre, Context, Result};
+use memmap2::{Mmap, MmapMut};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    files::{FileLayout, FileSpan},
+    Info,
+};
+
+/// Zeros written at once when preallocating a file
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// Where the pieces of a torrent are kept while downloading and seeding.
+/// Blocks are addressed by piece index and offset within the piece, the storage
+/// maps them onto its own layout. Calls block the calling thread, async code runs
+/// them through [`blocking`].
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Size of a piece, only the last one may be shorter than the piece length
+    fn piece_size(&self, piece: usize) -> usize;
+
+    /// Make room for all of the torrent's data up front
+    fn preallocate(&self) -> Result<()>;
+
+    /// Read `length` bytes at `begin` of `piece`
+    fn read_block(&self, piece: usize, begin: u32, length: usize) -> Result<Vec<u8>>;
+
+    /// Write `data` at `begin` of `piece`
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> Result<()>;
+
+    /// Make everything written so far durable
+    fn flush(&self) -> Result<()>;
+
+    /// Whether the stored piece hashes to `expected`
+    fn verify_piece(&self, piece: usize, expected: &[u8; 20]) -> Result<bool> {
+        let data = self.read_block(piece, 0, self.piece_size(piece))?;
+        Ok(<[u8; 20]>::from(Sha1::digest(&data)) == *expected)
+    }
+}
+
+/// Run storage calls on tokio's blocking thread pool
+pub async fn blocking<F, R>(storage: &Arc<dyn Storage>, f: F) -> Result<R>
+where
+    F: FnOnce(&dyn Storage) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || f(storage.as_ref()))
+        .await
+        .context("storage task")?
+}
+
+/// The storage backends to choose from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageKind {
+    /// Regular files that only take up disk space once data is written
+    #[default]
+    Sparse,
+    /// Regular files filled with zeros up front, so that the disk cannot run full midway
+    Preallocated,
+    /// Memory-mapped files
+    Mmap,
+}
+
+impl StorageKind {
+    /// Open storage of this kind for the files of `layout`
+    pub fn open(self, layout: FileLayout, info: &Info) -> Arc<dyn Storage> {
+        let piece_length = info.piece_length as u64;
+        match self {
+            StorageKind::Sparse => Arc::new(FileStorage::new(layout, piece_length)),
+            StorageKind::Preallocated => {
+                Arc::new(FileStorage::new(layout, piece_length).preallocated())
+            }
+            StorageKind::Mmap => Arc::new(MmapStorage::new(layout, piece_length)),
+        }
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sparse" => Ok(StorageKind::Sparse),
+            "preallocated" => Ok(StorageKind::Preallocated),
+            "mmap" => Ok(StorageKind::Mmap),
+            _ => Err(eyre!(
+                "unknown storage {s}, expected sparse, preallocated or mmap"
+            )),
+        }
+    }
+}
+
+/// Piece length and total length, to turn piece offsets into torrent offsets
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl Geometry {
+    fn piece_size(&self, piece: usize) -> usize {
+        let start = piece as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start)) as usize
+    }
+
+    /// Offset in the torrent of a block, which must lie within the torrent
+    fn offset(&self, piece: usize, begin: u32, length: usize) -> Result<u64> {
+        let offset = piece as u64 * self.piece_length + begin as u64;
+        if offset + length as u64 > self.total_length {
+            return Err(eyre!(
+                "block of {length} bytes at {begin} of piece {piece} is out of range"
+            ));
+        }
+        Ok(offset)
+    }
+}
+
+/// The torrent's files on disk, written with positional I/O.
+/// Open handles are kept for reuse, reads open files read-only so that
+/// read-only data can be seeded.
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: FileLayout,
+    geometry: Geometry,
+    preallocate_zeros: bool,
+    handles: Vec<Mutex<Option<Handle>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Handle {
+    file: Arc<File>,
+    writable: bool,
+}
+
+impl FileStorage {
+    /// Sparse files, preallocating only sets their lengths
+    pub fn new(layout: FileLayout, piece_length: u64) -> Self {
+        let geometry = Geometry {
+            piece_length,
+            total_length: layout.total_length(),
+        };
+        let handles = layout.files.iter().map(|_| Mutex::new(None)).collect();
+        Self {
+            layout,
+            geometry,
+            preallocate_zeros: false,
+            handles,
+        }
+    }
+
+    /// Write zeros over the not yet existing part of every file when preallocating
+    pub fn preallocated(mut self) -> Self {
+        self.preallocate_zeros = true;
+        self
+    }
+
+    fn handle(&self, index: usize, write: bool) -> Result<Arc<File>> {
+        let mut slot = self.handles[index].lock().unwrap();
+        if let Some(handle) = slot.as_ref() {
+            if handle.writable || !write {
+                return Ok(handle.file.clone());
+            }
+        }
+
+        let entry = &self.layout.files[index];
+        let file = if write {
+            create_parent(&entry.path)?;
+            open_writable(&entry.path)
+        } else {
+            File::open(&entry.path)
+        }
+        .with_context(|| format!("open {}", entry.path.display()))?;
+
+        let file = Arc::new(file);
+        *slot = Some(Handle {
+            file: file.clone(),
+            writable: write,
+        });
+        Ok(file)
+    }
+}
+
+impl Storage for FileStorage {
+    fn piece_size(&self, piece: usize) -> usize {
+        self.geometry.piece_size(piece)
+    }
+
+    fn preallocate(&self) -> Result<()> {
+        for (index, entry) in self.layout.files.iter().enumerate() {
+            let file = self.handle(index, true)?;
+            let current = file.metadata()?.len();
+            if current >= entry.length {
+                continue;
+            }
+
+            if self.preallocate_zeros {
+                let zeros = vec![0u8; ZERO_CHUNK];
+                let mut offset = current;
+                while offset < entry.length {
+                    let length = ZERO_CHUNK.min((entry.length - offset) as usize);
+                    write_all_at(&file, &zeros[..length], offset)
+                        .with_context(|| format!("preallocate {}", entry.path.display()))?;
+                    offset += length as u64;
+                }
+            } else {
+                file.set_len(entry.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, piece: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let offset = self.geometry.offset(piece, begin, length)?;
+        let mut data = vec![0u8; length];
+        for span in self.layout.spans(offset, length) {
+            let file = self.handle(span.file, false)?;
+            read_exact_at(&file, span.slice_mut(&mut data), span.file_offset)
+                .with_context(|| format!("read {}", self.layout.files[span.file].path.display()))?;
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self.geometry.offset(piece, begin, data.len())?;
+        for span in self.layout.spans(offset, data.len()) {
+            let file = self.handle(span.file, true)?;
+            write_all_at(&file, span.slice(data), span.file_offset).with_context(|| {
+                format!("write {}", self.layout.files[span.file].path.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for slot in &self.handles {
+            let handle = slot.lock().unwrap().clone();
+            if let Some(handle) = handle.filter(|handle| handle.writable) {
+                handle.file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The torrent's files mapped into memory. A file is mapped on first use and has
+/// to have its full length by then, preallocating creates the files.
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: FileLayout,
+    geometry: Geometry,
+    maps: Vec<Mutex<Option<Mapping>>>,
+}
+
+#[derive(Debug)]
+enum Mapping {
+    ReadOnly(Mmap),
+    Writable(MmapMut),
+}
+
+impl MmapStorage {
+    pub fn new(layout: FileLayout, piece_length: u64) -> Self {
+        let geometry = Geometry {
+            piece_length,
+            total_length: layout.total_length(),
+        };
+        let maps = layout.files.iter().map(|_| Mutex::new(None)).collect();
+        Self {
+            layout,
+            geometry,
+            maps,
+        }
+    }
+
+    /// Run `f` on the mapping of file `index`, mapping it writable if `write` is set
+    fn with_map<R>(
+        &self,
+        index: usize,
+        write: bool,
+        f: impl FnOnce(&mut Mapping) -> R,
+    ) -> Result<R> {
+        let mut slot = self.maps[index].lock().unwrap();
+        match slot.as_mut() {
+            Some(mapping @ Mapping::Writable(_)) => return Ok(f(mapping)),
+            Some(mapping) if !write => return Ok(f(mapping)),
+            _ => {}
+        }
+
+        let entry = &self.layout.files[index];
+        let file = if write {
+            create_parent(&entry.path)?;
+            open_writable(&entry.path)
+        } else {
+            File::open(&entry.path)
+        }
+        .with_context(|| format!("open {}", entry.path.display()))?;
+
+        let length = file.metadata()?.len();
+        if length != entry.length {
+            return Err(eyre!(
+                "{} has {length} bytes, expected {}",
+                entry.path.display(),
+                entry.length
+            ));
+        }
+
+        // SAFETY: the files belong to the torrent, changing them behind our back while
+        // they are mapped is not supported, like changing them during a download
+        let mapping = unsafe {
+            if write {
+                Mapping::Writable(MmapMut::map_mut(&file)?)
+            } else {
+                Mapping::ReadOnly(Mmap::map(&file)?)
+            }
+        };
+        Ok(f(slot.insert(mapping)))
+    }
+}
+
+impl Storage for MmapStorage {
+    fn piece_size(&self, piece: usize) -> usize {
+        self.geometry.piece_size(piece)
+    }
+
+    fn preallocate(&self) -> Result<()> {
+        for entry in &self.layout.files {
+            create_parent(&entry.path)?;
+            let file = open_writable(&entry.path)
+                .with_context(|| format!("open {}", entry.path.display()))?;
+            if file.metadata()?.len() < entry.length {
+                file.set_len(entry.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, piece: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let offset = self.geometry.offset(piece, begin, length)?;
+        let mut data = vec![0u8; length];
+        for span in self.layout.spans(offset, length) {
+            let range = span.file_offset as usize..span.file_offset as usize + span.length;
+            self.with_map(span.file, false, |mapping| {
+                let map: &[u8] = match mapping {
+                    Mapping::ReadOnly(map) => map,
+                    Mapping::Writable(map) => map,
+                };
+                span.slice_mut(&mut data).copy_from_slice(&map[range]);
+            })?;
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self.geometry.offset(piece, begin, data.len())?;
+        for span in self.layout.spans(offset, data.len()) {
+            let range = span.file_offset as usize..span.file_offset as usize + span.length;
+            self.with_map(span.file, true, |mapping| {
+                if let Mapping::Writable(map) = mapping {
+                    map[range].copy_from_slice(span.slice(data));
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for slot in &self.maps {
+            if let Some(Mapping::Writable(map)) = slot.lock().unwrap().as_ref() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the whole torrent in memory, for tests
+#[derive(Debug)]
+pub struct MemoryStorage {
+    geometry: Geometry,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(total_length: u64, piece_length: u64) -> Self {
+        Self {
+            geometry: Geometry {
+                piece_length,
+                total_length,
+            },
+            data: Mutex::new(vec![0; total_length as usize]),
+        }
+    }
+
+    /// Everything written so far, zeros where nothing was written
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn piece_size(&self, piece: usize) -> usize {
+        self.geometry.piece_size(piece)
+    }
+
+    fn preallocate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_block(&self, piece: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let offset = self.geometry.offset(piece, begin, length)? as usize;
+        Ok(self.data.lock().unwrap()[offset..offset + length].to_vec())
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self.geometry.offset(piece, begin, data.len())? as usize;
+        self.data.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSpan {
+    fn slice<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.range_offset..self.range_offset + self.length]
+    }
+
+    fn slice_mut<'a>(&self, data: &'a mut [u8]) -> &'a mut [u8] {
+        &mut data[self.range_offset..self.range_offset + self.length]
+    }
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("create directory {}", parent.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn open_writable(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let written = file.seek_write(buf, offset)?;
+        buf = &buf[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileInfo;
+
+    fn multi_file_info() -> Info {
+        Info {
+            name: "dir".to_string(),
+            length: 12,
+            files: Some(vec![
+                FileInfo {
+                    length: 5,
+                    path: vec!["a".to_string()],
+                },
+                FileInfo {
+                    length: 7,
+                    path: vec!["sub".to_string(), "b".to_string()],
+                },
+            ]),
+            piece_length: 4,
+            pieces: vec![0; 60],
+        }
+    }
+
+    /// Pieces written out of order come back the same, across file boundaries
+    fn round_trip(storage: &dyn Storage) {
+        storage.write_block(2, 0, b"89ab").unwrap();
+        storage.write_block(0, 0, b"0123").unwrap();
+        storage.write_block(1, 0, b"4567").unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read_block(0, 3, 4).unwrap(), b"3456");
+        assert_eq!(storage.read_block(2, 2, 2).unwrap(), b"ab");
+        assert!(storage.read_block(2, 2, 4).is_err());
+        assert!(storage.write_block(3, 0, b"x").is_err());
+
+        let hash = <[u8; 20]>::from(Sha1::digest(b"4567"));
+        assert!(storage.verify_piece(1, &hash).unwrap());
+        assert!(!storage.verify_piece(0, &hash).unwrap());
+    }
+
+    fn assert_files(dir: &Path) {
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"01234");
+        assert_eq!(
+            std::fs::read(dir.join("sub").join("b")).unwrap(),
+            b"56789ab"
+        );
+    }
+
+    #[test]
+    fn file_storage_writes_pieces_into_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = FileLayout::new(dir.path(), &multi_file_info()).unwrap();
+        assert_eq!(layout.files[1].path, dir.path().join("sub").join("b"));
+
+        let storage = FileStorage::new(layout, 4);
+        round_trip(&storage);
+        assert_files(dir.path());
+    }
+
+    #[test]
+    fn mmap_storage_writes_pieces_into_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = FileLayout::new(dir.path(), &multi_file_info()).unwrap();
+
+        let storage = MmapStorage::new(layout, 4);
+        // Files are only mapped with their full length
+        assert!(storage.write_block(0, 0, b"0123").is_err());
+        storage.preallocate().unwrap();
+        round_trip(&storage);
+        assert_files(dir.path());
+    }
+
+    #[test]
+    fn memory_storage_keeps_pieces() {
+        let storage = MemoryStorage::new(12, 4);
+        round_trip(&storage);
+        assert_eq!(storage.contents(), b"0123456789ab");
+        assert_eq!(storage.piece_size(2), 4);
+    }
+
+    #[test]
+    fn preallocation_keeps_existing_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, b"01").unwrap();
+
+        let mut info = multi_file_info();
+        info.files = None;
+        info.length = 3 * ZERO_CHUNK as i64 / 2;
+        for kind in [StorageKind::Sparse, StorageKind::Preallocated] {
+            let storage = kind.open(FileLayout::new(&path, &info).unwrap(), &info);
+            storage.preallocate().unwrap();
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(data.len(), info.length as usize);
+            assert_eq!(&data[..3], b"01\0");
+        }
+    }
+
+    #[test]
+    fn parses_storage_kinds() {
+        assert_eq!("mmap".parse::<StorageKind>().unwrap(), StorageKind::Mmap);
+        assert_eq!(
+            "preallocated".parse::<StorageKind>().unwrap(),
+            StorageKind::Preallocated
+        );
+        assert!("tape".parse::<StorageKind>().is_err());
+    }
+}
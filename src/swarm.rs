@@ -0,0 +1,1149 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use eyre::{eyre, Context, Result};
+use futures_util::SinkExt;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    choker::PeerStats,
+    codec::{PeerCodec, PeerStream},
+    downloader::{Downloader, PieceObserver, DEFAULT_PIPELINE_DEPTH},
+    error::Error,
+    extension::{Extended, ExtensionRegistry},
+    files::FileLayout,
+    handshake::Handshake,
+    peer_message::Message,
+    peers::Peer,
+    pex::{PexMessage, PexState, UT_PEX},
+    picker::{PiecePicker, RarestFirstPicker},
+    resume::{recheck, ResumeData},
+    storage::{blocking, Storage, StorageKind},
+    tracker::TorrentStats,
+    Peers, TorrentResponse,
+};
+
+/// Maximum number of peers downloaded from at the same time
+pub const MAX_PEERS: usize = 30;
+
+/// Time allowed for connecting and exchanging handshakes with a peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a choking peer has to unchoke us before it is dropped
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time allowed for a single piece before the peer is considered stalled
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long an idle peer waits before asking the picker again for pieces returned by others
+const IDLE_WAIT: Duration = Duration::from_millis(200);
+
+/// Resume data is saved at most this often while pieces arrive, and once at the end
+const RESUME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Pieces failing the hash check from the same peer before it is banned
+pub const MAX_HASH_FAILURES: usize = 3;
+
+/// Creates the piece picker for a torrent with the given number of pieces
+pub type PickerFactory = fn(usize) -> Box<dyn PiecePicker>;
+
+/// What happened to a download beyond pieces arriving, for callers that want to follow it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwarmEvent {
+    /// The data `peer` sent for `piece` did not match its hash, the piece is downloaded
+    /// again, from another peer if one has it
+    HashFailed { piece: usize, peer: SocketAddr },
+    /// `peer` sent bad data too often and was disconnected for the rest of the download
+    PeerBanned { peer: SocketAddr },
+}
+
+/// Downloads a torrent from many peers at once.
+/// Every peer runs in its own tokio task and asks a shared piece picker for work,
+/// pieces held by a peer that drops are handed out again to the remaining peers.
+/// Peers learned through peer exchange are connected to while there is room.
+///
+/// Verified pieces go to disk right away and are recorded in a resume file next to
+/// the output, a restarted download trusts it while the files are unchanged and
+/// hashes the existing data otherwise. Only the missing pieces are fetched.
+///
+/// Blocks are shared between the peers until their piece is verified. In endgame the
+/// remaining blocks are requested from several peers, a block one of them delivers is
+/// cancelled at the others right away and the piece completed from what arrived.
+/// A piece failing the hash check is blamed on the peer that sent all of its blocks,
+/// after [`MAX_HASH_FAILURES`] the peer is banned. Pieces put together from several
+/// peers are downloaded again without blaming anyone.
+#[derive(Debug, Clone)]
+pub struct Swarm {
+    max_peers: usize,
+    pipeline_depth: usize,
+    picker: PickerFactory,
+    recheck: bool,
+    storage: StorageKind,
+    events: Option<mpsc::UnboundedSender<SwarmEvent>>,
+}
+
+impl Default for Swarm {
+    fn default() -> Self {
+        Self {
+            max_peers: MAX_PEERS,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            picker: |num_pieces| Box::new(RarestFirstPicker::new(num_pieces)),
+            recheck: false,
+            storage: StorageKind::default(),
+            events: None,
+        }
+    }
+}
+
+impl Swarm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    /// Number of block requests kept outstanding per peer
+    pub fn with_pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth.max(1);
+        self
+    }
+
+    /// Replace the default rarest-first piece picker
+    pub fn with_picker(mut self, picker: PickerFactory) -> Self {
+        self.picker = picker;
+        self
+    }
+
+    /// Hash the existing data even if the resume data is valid
+    pub fn with_recheck(mut self, recheck: bool) -> Self {
+        self.recheck = recheck;
+        self
+    }
+
+    /// Replace the default sparse file storage
+    pub fn with_storage(mut self, storage: StorageKind) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Report hash failures and bans to `events`
+    pub fn with_events(mut self, events: mpsc::UnboundedSender<SwarmEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub async fn download(
+        &self,
+        output_path: &str,
+        torrent: TorrentResponse,
+        peers: Peers,
+    ) -> Result<()> {
+        let stats = TorrentStats::new(torrent.info.length as u64);
+        let (_, announced) = mpsc::unbounded_channel();
+        self.download_tracked(output_path, torrent, peers, stats, announced)
+            .await
+    }
+
+    /// Download while counting the transfer in `stats` for the tracker session,
+    /// peers from its re-announces arrive through `announced`
+    pub async fn download_tracked(
+        &self,
+        output_path: &str,
+        torrent: TorrentResponse,
+        peers: Peers,
+        stats: Arc<TorrentStats>,
+        mut announced: mpsc::UnboundedReceiver<SocketAddr>,
+    ) -> Result<()> {
+        let num_pieces = torrent.info.num_pieces();
+        let info_hash = torrent.info_hash()?;
+
+        let layout = FileLayout::new(output_path, &torrent.info)?;
+        let storage = self.storage.open(layout.clone(), &torrent.info);
+        let resume_path = ResumeData::path_for(output_path);
+        let resume = ResumeData::load(&resume_path).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring resume data: {:#}", e);
+            None
+        });
+        let mut have = match &resume {
+            Some(resume) if !self.recheck && resume.matches(&layout, info_hash, num_pieces) => {
+                resume.have(num_pieces)
+            }
+            _ if layout.files.iter().any(|file| file.path.exists()) => {
+                tracing::info!("Checking existing data of {}", torrent.info.name);
+                recheck(&storage, &torrent.info).await
+            }
+            _ => vec![false; num_pieces],
+        };
+        blocking(&storage, |storage| storage.preallocate()).await?;
+
+        let (pieces, mut receiver) = mpsc::channel(self.max_peers);
+        let (discovered, mut discoveries) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            blocks: Mutex::new(HashMap::new()),
+            deliveries: watch::Sender::new(()),
+            info_hash,
+            picker: Mutex::new((self.picker)(num_pieces)),
+            torrent,
+            depth: self.pipeline_depth,
+            stats,
+            pieces,
+            discovered,
+            connected: Mutex::new(HashSet::new()),
+            hash_failures: Mutex::new(HashMap::new()),
+            events: self.events.clone(),
+        });
+
+        // Every address is tried once, the ones beyond `max_peers` wait for a free slot
+        let mut known: HashSet<SocketAddr> = HashSet::new();
+        let mut waiting: VecDeque<SocketAddr> = VecDeque::new();
+        let mut workers = JoinSet::new();
+        let resume_peers = resume.as_ref().map(ResumeData::peers).unwrap_or_default();
+        for addr in peers.0.into_iter().chain(resume_peers) {
+            if known.insert(addr) {
+                waiting.push_back(addr);
+            }
+        }
+
+        let mut completed = 0;
+        {
+            let mut picker = shared.picker.lock().unwrap();
+            for piece in (0..num_pieces).filter(|&piece| have[piece]) {
+                picker.complete(piece);
+                shared
+                    .stats
+                    .record_piece(shared.torrent.info.piece_size(piece) as u64);
+                completed += 1;
+            }
+        }
+        if completed > 0 {
+            tracing::info!("Resuming with {}/{} pieces", completed, num_pieces);
+        }
+        let mut last_saved = Instant::now();
+        // Without peers only a tracker session announcing more can keep the download going
+        let mut announcing = true;
+
+        while completed < num_pieces {
+            while workers.len() < self.max_peers {
+                let Some(addr) = waiting.pop_front() else {
+                    break;
+                };
+                let shared = shared.clone();
+                workers.spawn(async move {
+                    if let Err(e) = Swarm::run_peer(Peer(addr), &shared).await {
+                        tracing::warn!("Peer {} dropped: {:#}", addr, e);
+                    }
+                    shared.connected.lock().unwrap().remove(&addr);
+                });
+            }
+            if workers.is_empty() && waiting.is_empty() && !announcing {
+                break;
+            }
+
+            tokio::select! {
+                Some((piece_index, piece)) = receiver.recv() => {
+                    Swarm::write_piece(&storage, piece_index, piece, &shared.stats).await?;
+                    have[piece_index] = true;
+                    completed += 1;
+                    tracing::info!("Piece {}/{} downloaded", completed, num_pieces);
+                    if last_saved.elapsed() >= RESUME_INTERVAL {
+                        Swarm::save_resume(&resume_path, &storage, &layout, info_hash, &have, shared.resume_peers(&known))
+                            .await;
+                        last_saved = Instant::now();
+                    }
+                }
+                Some(addr) = discoveries.recv() => {
+                    if known.insert(addr) {
+                        tracing::debug!("Learned about peer {}", addr);
+                        waiting.push_back(addr);
+                    }
+                }
+                addr = announced.recv(), if announcing => match addr {
+                    Some(addr) => {
+                        if known.insert(addr) {
+                            tracing::debug!("Tracker announced peer {}", addr);
+                            waiting.push_back(addr);
+                        }
+                    }
+                    None => announcing = false,
+                },
+                Some(_) = workers.join_next() => {
+                    if !workers.is_empty() || !waiting.is_empty() {
+                        continue;
+                    }
+                    // The last peer is gone, keep what it delivered before leaving
+                    while let Ok((piece_index, piece)) = receiver.try_recv() {
+                        Swarm::write_piece(&storage, piece_index, piece, &shared.stats).await?;
+                        have[piece_index] = true;
+                        completed += 1;
+                    }
+                    break;
+                }
+            }
+        }
+
+        workers.abort_all();
+        Swarm::save_resume(
+            &resume_path,
+            &storage,
+            &layout,
+            info_hash,
+            &have,
+            shared.resume_peers(&known),
+        )
+        .await;
+
+        if completed < num_pieces {
+            return Err(eyre!(
+                "all peers dropped, {} of {} pieces missing",
+                num_pieces - completed,
+                num_pieces
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn write_piece(
+        storage: &Arc<dyn Storage>,
+        piece_index: usize,
+        piece: Vec<u8>,
+        stats: &TorrentStats,
+    ) -> Result<()> {
+        let length = piece.len() as u64;
+        blocking(storage, move |storage| {
+            storage.write_block(piece_index, 0, &piece)
+        })
+        .await?;
+        stats.record_piece(length);
+        Ok(())
+    }
+
+    /// Record what is on disk, a failure only costs a recheck on the next run
+    async fn save_resume(
+        path: &Path,
+        storage: &Arc<dyn Storage>,
+        layout: &FileLayout,
+        info_hash: [u8; 20],
+        have: &[bool],
+        peers: Vec<SocketAddr>,
+    ) {
+        // The pieces have to be durable before the resume data claims them
+        let saved = blocking(storage, |storage| storage.flush())
+            .await
+            .and_then(|_| ResumeData::capture(layout, info_hash, have, &peers))
+            .and_then(|resume| resume.save(path));
+        if let Err(e) = saved {
+            tracing::warn!("Saving resume data failed: {:#}", e);
+        }
+    }
+
+    async fn run_peer(peer: Peer, shared: &Arc<Shared>) -> Result<()> {
+        let addr = peer.0;
+        let (stream, handshake) =
+            timeout(CONNECT_TIMEOUT, Handshake::connect(shared.info_hash, peer))
+                .await
+                .context("handshake timed out")??;
+        let mut stream = PeerCodec::framed(stream);
+
+        let mut extensions = ExtensionRegistry::new();
+        if handshake.supports_extensions() {
+            extensions.register(UT_PEX);
+            stream
+                .send(ExtensionRegistry::handshake_message(
+                    &extensions.handshake(),
+                )?)
+                .await?;
+        }
+
+        let mut state = PeerState {
+            addr,
+            shared: shared.clone(),
+            // Filled in by BITFIELD and HAVE, a peer without pieces may not send either
+            available: HashSet::new(),
+            failed: HashSet::new(),
+            choked: true,
+            stats: PeerStats::new(),
+            extensions,
+            pex: PexState::new(),
+        };
+        shared.picker.lock().unwrap().peer_joined(&state.available);
+        shared.connected.lock().unwrap().insert(addr);
+
+        let result = Swarm::download_pieces(&mut stream, &mut state).await;
+
+        shared.picker.lock().unwrap().peer_left(&state.available);
+        tracing::debug!(
+            "Downloaded {} bytes from {}",
+            state.stats.downloaded(),
+            addr
+        );
+        result
+    }
+
+    async fn download_pieces(stream: &mut PeerStream, state: &mut PeerState) -> Result<()> {
+        let shared = state.shared.clone();
+        Downloader::send(stream, Message::Interested).await?;
+
+        loop {
+            if state.choked {
+                timeout(UNCHOKE_TIMEOUT, Swarm::wait_for_unchoke(stream, state))
+                    .await
+                    .context("unchoke timed out")??;
+                state.choked = false;
+            }
+            state.send_pex(stream).await?;
+
+            let piece = {
+                let mut picker = shared.picker.lock().unwrap();
+                // Pieces this peer sent bad data for go to other peers first
+                let preferred =
+                    (!state.failed.is_empty()).then(|| &state.available - &state.failed);
+                let preferred = preferred.as_ref().unwrap_or(&state.available);
+                match picker
+                    .pick(preferred)
+                    .or_else(|| picker.pick(&state.available))
+                {
+                    Some(piece) => Some(piece),
+                    None if picker.has_work_for(&state.available) => None,
+                    None => return Ok(()),
+                }
+            };
+
+            let Some(piece) = piece else {
+                tokio::time::sleep(IDLE_WAIT).await;
+                continue;
+            };
+
+            let Ok(downloaded) = timeout(
+                PIECE_TIMEOUT,
+                Downloader::download_observed(
+                    stream,
+                    &shared.torrent,
+                    &(piece as i32),
+                    shared.depth,
+                    state,
+                ),
+            )
+            .await
+            else {
+                shared.picker.lock().unwrap().abort(piece);
+                return Err(eyre!("piece {piece} timed out"));
+            };
+
+            match downloaded {
+                Ok(Some(data)) => {
+                    shared.blocks.lock().unwrap().remove(&piece);
+                    let completed = shared.picker.lock().unwrap().complete(piece);
+                    // Other peers still on the piece cancel their requests
+                    shared.deliveries.send_replace(());
+                    // In endgame another peer may have won the race for this piece
+                    if !completed {
+                        continue;
+                    }
+                    if shared.pieces.send((piece, data)).await.is_err() {
+                        // The download finished or failed, nobody is waiting for pieces anymore
+                        return Ok(());
+                    }
+                }
+                // Cancelled, or choked and waiting for UNCHOKE again
+                Ok(None) => shared.picker.lock().unwrap().abort(piece),
+                Err(Error::HashMismatch { .. }) => {
+                    shared.picker.lock().unwrap().abort(piece);
+                    state.failed.insert(piece);
+                    let senders: HashSet<SocketAddr> = shared
+                        .blocks
+                        .lock()
+                        .unwrap()
+                        .remove(&piece)
+                        .unwrap_or_default()
+                        .into_values()
+                        .map(|(addr, _)| addr)
+                        .collect();
+                    if senders != HashSet::from([state.addr]) {
+                        tracing::warn!("Piece {} from several peers failed the hash check", piece);
+                    } else if shared.hash_failed(piece, state.addr) {
+                        return Err(eyre!("banned after {MAX_HASH_FAILURES} bad pieces"));
+                    }
+                }
+                Err(e) => {
+                    shared.picker.lock().unwrap().abort(piece);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    async fn wait_for_unchoke(stream: &mut PeerStream, state: &mut PeerState) -> Result<()> {
+        loop {
+            match Downloader::receive(stream).await? {
+                Message::Unchoke => return Ok(()),
+                Message::Bitfield(bitfield) => {
+                    for piece in Downloader::bitfield_pieces(&bitfield) {
+                        state.on_have(piece as usize);
+                    }
+                }
+                Message::Have { index } => state.on_have(index as usize),
+                Message::Extended { id, payload } => state.on_extended(id, payload),
+                message => tracing::debug!(
+                    "Ignoring {:?} from {} while waiting for UNCHOKE",
+                    message.id(),
+                    state.addr
+                ),
+            }
+        }
+    }
+}
+
+/// The blocks of a piece by offset, with the peer that sent each
+type PieceBlocks = HashMap<u32, (SocketAddr, Bytes)>;
+
+/// What all peer tasks of a download share
+struct Shared {
+    info_hash: [u8; 20],
+    torrent: TorrentResponse,
+    depth: usize,
+    picker: Mutex<Box<dyn PiecePicker>>,
+    /// Blocks of the pieces not verified yet
+    blocks: Mutex<HashMap<usize, PieceBlocks>>,
+    /// Bumped whenever a block arrives so that peers waiting for it can cancel theirs
+    deliveries: watch::Sender<()>,
+    /// Totals of the whole download, reported to trackers
+    stats: Arc<TorrentStats>,
+    /// Verified pieces on their way to disk
+    pieces: mpsc::Sender<(usize, Vec<u8>)>,
+    /// Peers learned from other peers
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+    /// Peers we exchanged handshakes with, offered to others through PEX
+    connected: Mutex<HashSet<SocketAddr>>,
+    /// Pieces that failed the hash check, by the peer that sent them
+    hash_failures: Mutex<HashMap<SocketAddr, usize>>,
+    events: Option<mpsc::UnboundedSender<SwarmEvent>>,
+}
+
+impl Shared {
+    /// Blame `peer` for a bad `piece`, returns whether the peer is banned now
+    fn hash_failed(&self, piece: usize, peer: SocketAddr) -> bool {
+        tracing::warn!("Piece {} from {} failed the hash check", piece, peer);
+        self.emit(SwarmEvent::HashFailed { piece, peer });
+
+        let mut hash_failures = self.hash_failures.lock().unwrap();
+        let failures = hash_failures.entry(peer).or_default();
+        *failures += 1;
+        if *failures < MAX_HASH_FAILURES {
+            return false;
+        }
+
+        tracing::warn!("Banning {} for sending bad data", peer);
+        self.emit(SwarmEvent::PeerBanned { peer });
+        true
+    }
+
+    /// Peers worth trying again on the next run, all but the banned ones
+    fn resume_peers(&self, known: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let hash_failures = self.hash_failures.lock().unwrap();
+        known
+            .iter()
+            .filter(|addr| hash_failures.get(addr).copied().unwrap_or(0) < MAX_HASH_FAILURES)
+            .copied()
+            .collect()
+    }
+
+    fn emit(&self, event: SwarmEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// What the swarm knows about one connected peer
+struct PeerState {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    available: HashSet<usize>,
+    /// Pieces this peer sent bad data for
+    failed: HashSet<usize>,
+    /// Peers start out choking us and may choke us again at any time
+    choked: bool,
+    stats: Arc<PeerStats>,
+    extensions: ExtensionRegistry,
+    pex: PexState,
+}
+
+impl PeerState {
+    /// Tell the peer about the peers we connected to and dropped, if it supports PEX
+    async fn send_pex(&mut self, stream: &mut PeerStream) -> Result<()> {
+        if !self.extensions.supports(UT_PEX) {
+            return Ok(());
+        }
+
+        let connected: HashSet<SocketAddr> = self
+            .shared
+            .connected
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&addr| addr != self.addr)
+            .copied()
+            .collect();
+        let Some(message) = self.pex.update(&connected, Instant::now()) else {
+            return Ok(());
+        };
+
+        let payload = Bytes::from(message.to_bytes()?);
+        Ok(Downloader::send(stream, self.extensions.message(UT_PEX, payload)?).await?)
+    }
+
+    fn on_extended(&mut self, id: u8, payload: Bytes) {
+        let message = match self.extensions.receive(id, payload) {
+            Ok(Extended::Message {
+                name: UT_PEX,
+                payload,
+            }) => PexMessage::from_bytes(&payload),
+            Ok(_) => return,
+            Err(e) => Err(e),
+        };
+
+        match message {
+            Ok(message) => {
+                for (addr, _flags) in message.added {
+                    let _ = self.shared.discovered.send(addr);
+                }
+            }
+            Err(e) => tracing::debug!("Bad extended message from {}: {:#}", self.addr, e),
+        }
+    }
+}
+
+impl PieceObserver for PeerState {
+    fn on_have(&mut self, piece: usize) {
+        // Spare bits of a BITFIELD or a bogus HAVE may lie past the last piece
+        if piece < self.shared.torrent.info.num_pieces() && self.available.insert(piece) {
+            self.shared.picker.lock().unwrap().peer_has(piece);
+        }
+    }
+
+    fn on_block(&mut self, piece: usize, begin: u32, block: &Bytes) {
+        self.stats.record_download(block.len() as u64);
+        self.shared.stats.record_download(block.len() as u64);
+
+        if self.is_cancelled(piece) {
+            return;
+        }
+        // The first copy of a block is kept, the others arrived despite a CANCEL
+        let mut blocks = self.shared.blocks.lock().unwrap();
+        if let Entry::Vacant(entry) = blocks.entry(piece).or_default().entry(begin) {
+            entry.insert((self.addr, block.clone()));
+            self.shared.deliveries.send_replace(());
+        }
+    }
+
+    fn on_extended(&mut self, id: u8, payload: Bytes) {
+        PeerState::on_extended(self, id, payload);
+    }
+
+    fn on_choke(&mut self) {
+        tracing::debug!("Choked by {}", self.addr);
+        self.choked = true;
+    }
+
+    fn is_cancelled(&self, piece: usize) -> bool {
+        self.shared.picker.lock().unwrap().is_complete(piece)
+    }
+
+    fn delivered(&self, piece: usize, begin: u32) -> Option<Bytes> {
+        let blocks = self.shared.blocks.lock().unwrap();
+        blocks
+            .get(&piece)?
+            .get(&begin)
+            .map(|(_, block)| block.clone())
+    }
+
+    fn deliveries(&self) -> Option<watch::Receiver<()>> {
+        Some(self.shared.deliveries.subscribe())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{future::Future, path::PathBuf};
+
+    use futures_util::{FutureExt, StreamExt};
+    use sha1::{Digest, Sha1};
+    use tempfile::TempDir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{extension::ExtendedHandshake, pex::FLAG_SEED, seeder::Seeder, Info};
+
+    /// A single-file torrent of generated data, the data written to a source file
+    /// seeders serve from, and where downloads of it go
+    pub(crate) struct Fixture {
+        pub data: Vec<u8>,
+        pub torrent: TorrentResponse,
+        pub source: PathBuf,
+        pub output: PathBuf,
+        _dir: TempDir,
+    }
+
+    impl Fixture {
+        pub fn new(length: usize, piece_length: usize) -> Self {
+            let data: Vec<u8> = (0..length).map(|i| (i * 7 % 251) as u8).collect();
+            let info = Info {
+                name: "data.bin".to_string(),
+                length: length as i64,
+                files: None,
+                piece_length: piece_length as i64,
+                pieces: data
+                    .chunks(piece_length)
+                    .flat_map(|piece| Sha1::digest(piece).to_vec())
+                    .collect(),
+            };
+            let raw_info = serde_bencode::to_bytes(&info).unwrap();
+            let torrent = TorrentResponse {
+                info,
+                announce_url: String::new(),
+                announce_list: vec![],
+                hash: hex::encode(Sha1::digest(&raw_info)),
+                raw_info,
+            };
+
+            let dir = tempfile::tempdir().unwrap();
+            let source = dir.path().join("source.bin");
+            std::fs::write(&source, &data).unwrap();
+            Fixture {
+                data,
+                torrent,
+                source,
+                output: dir.path().join("output.bin"),
+                _dir: dir,
+            }
+        }
+
+        pub fn num_pieces(&self) -> usize {
+            self.torrent.info.num_pieces()
+        }
+
+        /// The PIECE message answering a request for the block at `begin` of `index`
+        pub fn block(&self, index: u32, begin: u32, length: u32) -> Message {
+            let start = index as usize * self.torrent.info.piece_length as usize + begin as usize;
+            Message::Piece {
+                index,
+                begin,
+                block: Bytes::copy_from_slice(&self.data[start..start + length as usize]),
+            }
+        }
+
+        /// Serve the torrent from `seeder` on `bind`
+        pub async fn serve(&self, mut seeder: Seeder, bind: &str) -> SocketAddr {
+            seeder
+                .add_torrent(self.torrent.clone(), &self.source)
+                .await
+                .unwrap();
+            spawn_seeder(seeder, bind).await
+        }
+
+        /// Serve the torrent from a seeder with default settings
+        pub async fn seed(&self) -> SocketAddr {
+            self.serve(Seeder::new(), "127.0.0.1:0").await
+        }
+
+        pub async fn download(&self, swarm: Swarm, peers: Vec<SocketAddr>) -> Result<()> {
+            swarm
+                .download(
+                    self.output.to_str().unwrap(),
+                    self.torrent.clone(),
+                    Peers(peers),
+                )
+                .await
+        }
+
+        pub fn assert_downloaded(&self) {
+            assert_eq!(std::fs::read(&self.output).unwrap(), self.data);
+        }
+    }
+
+    async fn spawn_seeder(seeder: Seeder, bind: &str) -> SocketAddr {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(seeder).run(listener));
+        addr
+    }
+
+    /// A peer following `script` on every connection, once the handshake it gets
+    /// has been sent back
+    async fn spawn_peer<F, Fut>(script: F) -> SocketAddr
+    where
+        F: Fn(PeerStream) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut handshake = [0u8; 68];
+                if socket.read_exact(&mut handshake).await.is_err()
+                    || socket.write_all(&handshake).await.is_err()
+                {
+                    continue;
+                }
+                tokio::spawn(script(PeerCodec::framed(socket)));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn downloads_from_seeders() {
+        let fixture = Fixture::new(100_000, 32 * 1024);
+        // Two seeders with one upload slot each
+        let mut addrs = vec![];
+        for _ in 0..2 {
+            let seeder = Seeder::new().with_upload_slots(1);
+            addrs.push(fixture.serve(seeder, "127.0.0.1:0").await);
+        }
+
+        fixture.download(Swarm::new(), addrs).await.unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn endgame_requests_and_cancels_single_blocks() {
+        let fixture = Arc::new(Fixture::new(64 * 1024, 64 * 1024));
+        let (cancels, mut cancelled) = mpsc::unbounded_channel();
+        let (requests, mut requested) = mpsc::unbounded_channel();
+
+        // Has the piece first but stops after its first block
+        let served = fixture.clone();
+        let stalling = spawn_peer(move |mut peer| {
+            let (fixture, cancels) = (served.clone(), cancels.clone());
+            async move {
+                peer.send(Message::Bitfield(vec![0x80])).await?;
+                while let Some(message) = peer.next().await {
+                    match message? {
+                        Message::Interested => peer.send(Message::Unchoke).await?,
+                        Message::Request {
+                            index,
+                            begin: 0,
+                            length,
+                        } => peer.send(fixture.block(index, 0, length)).await?,
+                        Message::Cancel { begin, .. } => cancels.send(begin)?,
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        // Unchokes later, once the piece is in flight, and answers slowly
+        let served = fixture.clone();
+        let slow = spawn_peer(move |mut peer| {
+            let (fixture, requests) = (served.clone(), requests.clone());
+            async move {
+                peer.send(Message::Bitfield(vec![0x80])).await?;
+                while let Some(message) = peer.next().await {
+                    match message? {
+                        Message::Interested => {
+                            tokio::time::sleep(Duration::from_millis(300)).await;
+                            peer.send(Message::Unchoke).await?;
+                        }
+                        Message::Request {
+                            index,
+                            begin,
+                            length,
+                        } => {
+                            requests.send(begin)?;
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            peer.send(fixture.block(index, begin, length)).await?;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        fixture
+            .download(Swarm::new(), vec![stalling, slow])
+            .await
+            .unwrap();
+        fixture.assert_downloaded();
+
+        // Only the blocks still missing were requested again
+        requested.close();
+        let mut requests = vec![];
+        while let Some(begin) = requested.recv().await {
+            requests.push(begin);
+        }
+        assert_eq!(requests, [16 * 1024, 32 * 1024, 48 * 1024]);
+        // A block delivered by one peer is cancelled at the other right away
+        let first_cancel = timeout(Duration::from_secs(1), cancelled.recv()).await;
+        assert_eq!(first_cancel.unwrap(), Some(16 * 1024));
+    }
+
+    #[tokio::test]
+    async fn gives_up_without_peers() {
+        let fixture = Fixture::new(20_000, 16 * 1024);
+        let result = timeout(
+            Duration::from_secs(5),
+            fixture.download(Swarm::new(), vec![]),
+        )
+        .await
+        .expect("the download should not wait for peers that cannot arrive");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("all peers dropped, 2 of 2 pieces missing"));
+    }
+
+    #[tokio::test]
+    async fn downloads_over_ipv6() {
+        let fixture = Fixture::new(40_000, 16 * 1024);
+        let addr = fixture.serve(Seeder::new(), "[::1]:0").await;
+
+        fixture.download(Swarm::new(), vec![addr]).await.unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn bans_peers_sending_bad_data() {
+        let fixture = Fixture::new(80_000, 16 * 1024);
+        // Claims every piece and answers each request with garbage
+        let num_pieces = fixture.num_pieces();
+        let corrupt_addr = spawn_peer(move |mut peer| async move {
+            peer.send(Message::Bitfield(vec![0xff; num_pieces.div_ceil(8)]))
+                .await?;
+            while let Some(message) = peer.next().await {
+                match message? {
+                    Message::Interested => peer.send(Message::Unchoke).await?,
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    } => {
+                        let block = Bytes::from(vec![0xee; length as usize]);
+                        peer.send(Message::Piece {
+                            index,
+                            begin,
+                            block,
+                        })
+                        .await?
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await;
+
+        // On its own the corrupt peer only gets itself banned
+        let (events, mut received) = mpsc::unbounded_channel();
+        let swarm = Swarm::new().with_events(events);
+        assert!(fixture.download(swarm, vec![corrupt_addr]).await.is_err());
+
+        let mut failed = HashSet::new();
+        for _ in 0..MAX_HASH_FAILURES {
+            let Some(SwarmEvent::HashFailed { piece, peer }) = received.recv().await else {
+                panic!("expected a hash failure");
+            };
+            assert_eq!(peer, corrupt_addr);
+            failed.insert(piece);
+        }
+        // Every retry went to a piece the peer had not failed yet
+        assert_eq!(failed.len(), MAX_HASH_FAILURES);
+        assert_eq!(
+            received.recv().await,
+            Some(SwarmEvent::PeerBanned { peer: corrupt_addr })
+        );
+
+        // Next to a seeder the bad pieces are fetched again from it
+        let seeder_addr = fixture.seed().await;
+        let swarm = Swarm::new().with_recheck(true);
+        fixture
+            .download(swarm, vec![corrupt_addr, seeder_addr])
+            .await
+            .unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn downloads_into_mmap_storage() {
+        let fixture = Fixture::new(60_000, 16 * 1024);
+        let seeder = Seeder::new().with_storage(StorageKind::Mmap);
+        let addr = fixture.serve(seeder, "127.0.0.1:0").await;
+
+        let swarm = Swarm::new().with_storage(StorageKind::Mmap);
+        fixture.download(swarm, vec![addr]).await.unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn resumes_partial_downloads() {
+        let fixture = Fixture::new(70_000, 16 * 1024);
+        let mut seeder = Seeder::new();
+        seeder
+            .add_torrent(fixture.torrent.clone(), &fixture.source)
+            .await
+            .unwrap();
+        let stats = seeder.stats(&fixture.torrent.info_hash().unwrap()).unwrap();
+        let addr = spawn_seeder(seeder, "127.0.0.1:0").await;
+
+        // An earlier run got the first two pieces but left no resume data
+        let done = 2 * 16 * 1024;
+        let mut partial = fixture.data[..done].to_vec();
+        partial.resize(fixture.data.len(), 0);
+        std::fs::write(&fixture.output, &partial).unwrap();
+
+        fixture.download(Swarm::new(), vec![addr]).await.unwrap();
+        fixture.assert_downloaded();
+        assert_eq!(stats.uploaded(), (fixture.data.len() - done) as u64);
+
+        // The resume data now says everything is there, no peer is needed
+        let resume = ResumeData::load(ResumeData::path_for(&fixture.output))
+            .unwrap()
+            .unwrap();
+        assert_eq!(resume.have(5), vec![true; 5]);
+        fixture.download(Swarm::new(), vec![]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_seeders_through_pex() {
+        let fixture = Fixture::new(50_000, 16 * 1024);
+        let seeder_addr = fixture.seed().await;
+
+        // A peer without pieces that only tells the swarm about the seeder
+        let gossip_addr = spawn_peer(move |mut peer| async move {
+            peer.send(Message::Bitfield(vec![0; 1])).await?;
+            peer.send(Message::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md6:ut_pexi1eee"),
+            })
+            .await?;
+
+            // Our first extended message is the handshake telling us the id to use
+            let Some(Ok(Message::Extended { id: 0, payload })) = peer.next().await else {
+                return Err(eyre!("expected the extended handshake"));
+            };
+            let handshake = ExtendedHandshake::from_bytes(&payload)?;
+            let pex = PexMessage {
+                added: vec![(seeder_addr, FLAG_SEED)],
+                dropped: vec![],
+            };
+            peer.send(Message::Extended {
+                id: handshake.m[UT_PEX] as u8,
+                payload: Bytes::from(pex.to_bytes()?),
+            })
+            .await?;
+            while peer.next().await.is_some() {}
+            Ok(())
+        })
+        .await;
+
+        fixture
+            .download(Swarm::new(), vec![gossip_addr])
+            .await
+            .unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn accepts_peers_without_a_bitfield() {
+        let fixture = Arc::new(Fixture::new(40_000, 16 * 1024));
+
+        // Extended handshake first like Transmission, then HAVEs instead of a BITFIELD
+        let served = fixture.clone();
+        let addr = spawn_peer(move |mut peer| {
+            let fixture = served.clone();
+            async move {
+                peer.send(Message::Extended {
+                    id: 0,
+                    payload: Bytes::from_static(b"d1:mdee"),
+                })
+                .await?;
+                while let Some(message) = peer.next().await {
+                    match message? {
+                        Message::Interested => {
+                            for index in 0..fixture.num_pieces() as u32 {
+                                peer.send(Message::Have { index }).await?;
+                            }
+                            peer.send(Message::Unchoke).await?;
+                        }
+                        Message::Request {
+                            index,
+                            begin,
+                            length,
+                        } => peer.send(fixture.block(index, begin, length)).await?,
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        fixture.download(Swarm::new(), vec![addr]).await.unwrap();
+        fixture.assert_downloaded();
+    }
+
+    #[tokio::test]
+    async fn waits_for_unchoke_again_after_a_choke() {
+        let fixture = Arc::new(Fixture::new(40_000, 16 * 1024));
+
+        // Stray messages before the UNCHOKE, then a CHOKE on the first request
+        let served = fixture.clone();
+        let addr = spawn_peer(move |mut peer| {
+            let fixture = served.clone();
+            async move {
+                let mut choked = false;
+                while let Some(message) = peer.next().await {
+                    match message? {
+                        Message::Interested => {
+                            peer.send(Message::Bitfield(vec![0b1110_0000])).await?;
+                            peer.send(Message::Choke).await?;
+                            peer.send(Message::NotInterested).await?;
+                            peer.send(Message::Port(6881)).await?;
+                            peer.send(Message::Unchoke).await?;
+                        }
+                        Message::Request { .. } if !choked => {
+                            choked = true;
+                            peer.send(Message::Choke).await?;
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            // Requests sent before the CHOKE arrived are dropped
+                            while let Some(Some(_)) = peer.next().now_or_never() {}
+                            peer.send(Message::Unchoke).await?;
+                        }
+                        Message::Request {
+                            index,
+                            begin,
+                            length,
+                        } => peer.send(fixture.block(index, begin, length)).await?,
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        fixture.download(Swarm::new(), vec![addr]).await.unwrap();
+        fixture.assert_downloaded();
+    }
+}
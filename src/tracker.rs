@@ -0,0 +1,848 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Result};
+use futures_util::future::join_all;
+use rand::seq::SliceRandom;
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
+    time::{sleep_until, timeout},
+};
+
+use crate::{peers::Peer, Peers, TorrentResponse, TrackerEvent, TrackerRequest, TrackerResponse};
+
+/// Re-announce interval when the tracker does not ask for one
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Wait before announcing again after no tracker responded
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time a tracker gets to answer before the next one of its tier is tried,
+/// a dead UDP tracker would otherwise be retransmitted to for hours
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed for the `stopped` announce when shutting down
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The trackers of a torrent grouped in tiers (BEP 12)
+/// https://www.bittorrent.org/beps/bep_0012.html
+///
+/// Trackers within a tier are shuffled once, a tracker that answers moves to the
+/// front of its tier so it is tried first next time. The tiers are walked in order,
+/// trying the trackers of each until one answers, and the peers of all tiers are
+/// merged. The announce only fails if no tier had a responsive tracker.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnnounceList {
+    tiers: Vec<Vec<String>>,
+    concurrent_tiers: bool,
+}
+
+impl AnnounceList {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<String> =
+                    tier.into_iter().filter(|url| !url.is_empty()).collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        AnnounceList {
+            tiers,
+            concurrent_tiers: false,
+        }
+    }
+
+    /// Announce to all tiers at the same time instead of one after the other, so that a
+    /// tier of dead trackers does not hold up the next one. Not what BEP 12 asks for.
+    pub fn with_concurrent_tiers(mut self) -> Self {
+        self.concurrent_tiers = true;
+        self
+    }
+
+    /// The `announce-list` of the torrent, or its single `announce` URL without one
+    pub fn from_torrent(torrent: &TorrentResponse) -> Self {
+        if torrent.announce_list.is_empty() {
+            AnnounceList::new(vec![vec![torrent.announce_url.clone()]])
+        } else {
+            AnnounceList::new(torrent.announce_list.clone())
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Announce to the trackers of every tier, see [`AnnounceList::announce_with`]
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        self.announce_with(|url| async move { Ok(Peer::announce(&url, info_hash, request).await?) })
+            .await
+    }
+
+    /// Announce through the tiers with `announce`, called with the URL of each
+    /// tracker tried. Returns the peers of all tiers, without duplicates, and the
+    /// shortest interval any tracker asked for.
+    pub async fn announce_with<F, Fut>(&mut self, announce: F) -> Result<TrackerResponse>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<TrackerResponse>>,
+    {
+        self.announce_within(ANNOUNCE_TIMEOUT, announce).await
+    }
+
+    /// [`AnnounceList::announce_with`], giving up on a tracker after `limit`
+    async fn announce_within<F, Fut>(
+        &mut self,
+        limit: Duration,
+        announce: F,
+    ) -> Result<TrackerResponse>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<TrackerResponse>>,
+    {
+        // Concurrent tiers take turns calling `announce`, never holding it across an await
+        let announce = Mutex::new(announce);
+        let concurrent = self.concurrent_tiers;
+        let tiers = self.tiers.iter_mut().map(|tier| {
+            let announce = &announce;
+            async move {
+                let mut last_error = eyre!("tier has no trackers");
+                for index in 0..tier.len() {
+                    let attempt = (announce.lock().unwrap())(tier[index].clone());
+                    match timeout(limit, attempt).await {
+                        Ok(Ok(response)) => {
+                            // The responsive tracker is tried first from now on
+                            let url = tier.remove(index);
+                            tier.insert(0, url);
+                            return Ok(response);
+                        }
+                        Ok(Err(e)) => {
+                            tracing::warn!("Announce to {} failed: {:#}", tier[index], e);
+                            last_error = e;
+                        }
+                        Err(_) => {
+                            tracing::warn!("Announce to {} timed out", tier[index]);
+                            last_error = eyre!("{} did not answer in time", tier[index]);
+                        }
+                    }
+                }
+                Err(last_error)
+            }
+        });
+
+        let mut interval: Option<usize> = None;
+        let mut min_interval = None;
+        let mut tracker_id = None;
+        let mut peers = vec![];
+        let mut peer_ids = HashMap::new();
+        let mut last_error = None;
+
+        let results = if concurrent {
+            join_all(tiers).await
+        } else {
+            let mut results = vec![];
+            for tier in tiers {
+                results.push(tier.await);
+            }
+            results
+        };
+
+        for result in results {
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            interval = Some(interval.map_or(response.interval, |interval| {
+                interval.min(response.interval)
+            }));
+            min_interval = min_interval.max(response.min_interval);
+            tracker_id = tracker_id.or(response.tracker_id);
+            for peer in response.peers.0 {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            peer_ids.extend(response.peer_ids);
+        }
+
+        match (interval, last_error) {
+            (Some(interval), _) => Ok(TrackerResponse {
+                interval,
+                min_interval,
+                peers: Peers(peers),
+                peer_ids,
+                tracker_id,
+                ..Default::default()
+            }),
+            (None, Some(e)) => Err(e.wrap_err("no tracker responded")),
+            (None, None) => Err(eyre!("no trackers to announce to")),
+        }
+    }
+}
+
+/// Transfer totals of a torrent, updated by the download engine and reported to trackers
+#[derive(Debug, Default)]
+pub struct TorrentStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    left: AtomicU64,
+    completed: Notify,
+}
+
+impl TorrentStats {
+    /// Stats of a torrent with `left` bytes still to be downloaded and verified
+    pub fn new(left: u64) -> Arc<Self> {
+        Arc::new(Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        })
+    }
+
+    /// Block data received from any peer, including pieces that fail verification
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Block data sent to any peer
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A verified piece of `bytes` was stored
+    pub fn record_piece(&self, bytes: u64) {
+        let left = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            })
+            .unwrap();
+        if left > 0 && left <= bytes {
+            self.completed.notify_one();
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.left() == 0
+    }
+
+    /// Resolves once nothing is left to download
+    pub async fn wait_complete(&self) {
+        if !self.is_complete() {
+            self.completed.notified().await;
+        }
+    }
+}
+
+/// Announcing a torrent for as long as it is downloaded or seeded.
+/// `started` goes out first, regular announces follow every `interval`, `completed`
+/// as soon as the download finishes and `stopped` at the end. `min interval` does not
+/// hold back events (BEP 3), a `completed` that fails is retried with the regular
+/// announces and sent before `stopped` if still due. Every announce reports the
+/// current [`TorrentStats`].
+#[derive(Debug)]
+pub struct TrackerSession {
+    trackers: AnnounceList,
+    info_hash: [u8; 20],
+    stats: Arc<TorrentStats>,
+    tracker_id: Option<String>,
+    last_announce: Option<Instant>,
+    next_announce: Instant,
+    min_interval: Duration,
+    /// Only a download that started incomplete sends `completed`
+    pending_completed: bool,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: AnnounceList, info_hash: [u8; 20], stats: Arc<TorrentStats>) -> Self {
+        Self {
+            trackers,
+            info_hash,
+            pending_completed: !stats.is_complete(),
+            stats,
+            tracker_id: None,
+            last_announce: None,
+            next_announce: Instant::now(),
+            min_interval: Duration::ZERO,
+        }
+    }
+
+    /// The request for an announce with `event` and the current stats
+    pub fn request(&self, event: Option<TrackerEvent>) -> TrackerRequest {
+        TrackerRequest {
+            uploaded: self.stats.uploaded() as usize,
+            downloaded: self.stats.downloaded() as usize,
+            event,
+            tracker_id: self.tracker_id.clone(),
+            ..TrackerRequest::new(self.stats.left() as usize)
+        }
+    }
+
+    /// When the next regular announce is due
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    /// The earliest time another announce may be sent, per the tracker's `min interval`
+    pub fn earliest_announce(&self) -> Instant {
+        self.last_announce
+            .map_or_else(Instant::now, |last| last + self.min_interval)
+    }
+
+    /// `completed` if the download finished and no tracker was told yet
+    fn due_completed(&self) -> Option<TrackerEvent> {
+        (self.pending_completed && self.stats.is_complete()).then_some(TrackerEvent::Completed)
+    }
+
+    /// Send the `started` announce, returning the first peers
+    pub async fn start(&mut self) -> Result<TrackerResponse> {
+        self.announce(Some(TrackerEvent::Started)).await
+    }
+
+    pub async fn announce(&mut self, event: Option<TrackerEvent>) -> Result<TrackerResponse> {
+        let info_hash = self.info_hash;
+        self.announce_with(event, |url, request| async move {
+            Ok(Peer::announce(&url, &info_hash, &request).await?)
+        })
+        .await
+    }
+
+    /// Announce `event` through the trackers with `announce`, called with the URL of
+    /// each tracker tried and the request to send it
+    pub async fn announce_with<F, Fut>(
+        &mut self,
+        event: Option<TrackerEvent>,
+        mut announce: F,
+    ) -> Result<TrackerResponse>
+    where
+        F: FnMut(String, TrackerRequest) -> Fut,
+        Fut: Future<Output = Result<TrackerResponse>>,
+    {
+        let request = self.request(event);
+        let result = self
+            .trackers
+            .announce_with(|url| announce(url, request.clone()))
+            .await;
+
+        let now = Instant::now();
+        self.last_announce = Some(now);
+        match &result {
+            Ok(response) => {
+                let interval = match response.interval {
+                    0 => DEFAULT_INTERVAL,
+                    interval => Duration::from_secs(interval as u64),
+                };
+                self.min_interval = response
+                    .min_interval
+                    .map_or(Duration::ZERO, |min| Duration::from_secs(min as u64));
+                self.next_announce = now + interval.max(self.min_interval);
+                if response.tracker_id.is_some() {
+                    self.tracker_id = response.tracker_id.clone();
+                }
+                if event == Some(TrackerEvent::Completed) {
+                    self.pending_completed = false;
+                }
+            }
+            Err(_) => self.next_announce = now + RETRY_INTERVAL.max(self.min_interval),
+        }
+        result
+    }
+
+    /// Keep announcing in the background, sending the peers of every announce to `peers`
+    pub fn spawn(mut self, peers: mpsc::UnboundedSender<SocketAddr>) -> TrackerHandle {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            if self.trackers.is_empty() {
+                return;
+            }
+
+            // After a failed `completed` only the regular announces retry it
+            let mut completed_failed = false;
+            loop {
+                let stats = self.stats.clone();
+                let event = tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = sleep_until(self.next_announce().into()) => self.due_completed(),
+                    _ = stats.wait_complete(), if self.pending_completed && !completed_failed => {
+                        Some(TrackerEvent::Completed)
+                    }
+                };
+
+                match self.announce(event).await {
+                    Ok(response) => {
+                        for peer in response.peers.0 {
+                            let _ = peers.send(peer);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("Announce failed: {:#}", e);
+                        completed_failed |= event == Some(TrackerEvent::Completed);
+                    }
+                }
+            }
+
+            let shutdown = async {
+                // The download may have finished just before stopping
+                if let Some(event) = self.due_completed() {
+                    if let Err(e) = self.announce(Some(event)).await {
+                        tracing::warn!("Announcing completed failed: {:#}", e);
+                    }
+                }
+                self.announce(Some(TrackerEvent::Stopped)).await
+            };
+            // A tracker that does not answer should not hold up shutting down
+            match timeout(STOPPED_TIMEOUT, shutdown).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::warn!("Announcing stopped failed: {:#}", e),
+                Err(_) => tracing::warn!("Announcing stopped timed out"),
+            }
+        });
+
+        TrackerHandle { stop, task }
+    }
+}
+
+/// A [`TrackerSession`] announcing in the background
+#[derive(Debug)]
+pub struct TrackerHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerHandle {
+    /// Send `stopped` and wait for the session to end
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn response(interval: usize, peers: &[&str]) -> TrackerResponse {
+        TrackerResponse {
+            interval,
+            peers: Peers(peers.iter().map(|peer| peer.parse().unwrap()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn promotes_responsive_tracker_within_tier() {
+        let mut list = AnnounceList {
+            tiers: tiers(&[&["http://down", "http://up"]]),
+            ..Default::default()
+        };
+
+        let mut tried = vec![];
+        let response = list
+            .announce_with(|url| {
+                tried.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "http://up" => Ok(response(1800, &["10.0.0.1:6881"])),
+                        _ => Err(eyre!("unreachable")),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(tried, vec!["http://down", "http://up"]);
+        assert_eq!(response.peers.0.len(), 1);
+        assert_eq!(list.tiers(), tiers(&[&["http://up", "http://down"]]));
+    }
+
+    #[tokio::test]
+    async fn merges_peers_across_tiers() {
+        let mut list = AnnounceList {
+            tiers: tiers(&[&["http://a"], &["http://b", "http://c"], &["http://d"]]),
+            ..Default::default()
+        };
+
+        let response = list
+            .announce_with(|url| async move {
+                match url.as_str() {
+                    "http://a" => Ok(response(1800, &["10.0.0.1:1", "10.0.0.2:2"])),
+                    "http://b" => Err(eyre!("timed out")),
+                    "http://c" => Ok(response(900, &["10.0.0.2:2", "10.0.0.3:3"])),
+                    _ => Err(eyre!("unreachable")),
+                }
+            })
+            .await
+            .unwrap();
+
+        let expected: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:2", "10.0.0.3:3"]
+            .iter()
+            .map(|peer| peer.parse().unwrap())
+            .collect();
+        assert_eq!(response.peers.0, expected);
+        assert_eq!(response.interval, 900);
+        assert_eq!(list.tiers()[0], vec!["http://a"]);
+        assert_eq!(list.tiers()[1], vec!["http://c", "http://b"]);
+    }
+
+    #[tokio::test]
+    async fn walks_tiers_in_order_and_skips_hanging_trackers() {
+        let mut list = AnnounceList {
+            tiers: tiers(&[&["http://hangs", "http://a"], &["http://b"]]),
+            ..Default::default()
+        };
+
+        let mut tried = vec![];
+        let response = list
+            .announce_within(Duration::from_millis(100), |url| {
+                tried.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "http://hangs" => std::future::pending().await,
+                        "http://a" => Ok(response(1800, &["10.0.0.1:1"])),
+                        _ => Ok(response(1800, &["10.0.0.2:2"])),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        // The second tier waits for the first to find a responsive tracker
+        assert_eq!(tried, vec!["http://hangs", "http://a", "http://b"]);
+        assert_eq!(response.peers.0.len(), 2);
+        assert_eq!(list.tiers()[0], vec!["http://a", "http://hangs"]);
+    }
+
+    #[tokio::test]
+    async fn announces_to_tiers_concurrently_when_asked_to() {
+        let mut list = AnnounceList {
+            tiers: tiers(&[&["http://hangs", "http://a"], &["http://b"]]),
+            ..Default::default()
+        }
+        .with_concurrent_tiers();
+
+        let started = Instant::now();
+        let response = list
+            .announce_within(Duration::from_millis(300), |url| async move {
+                match url.as_str() {
+                    "http://hangs" => std::future::pending().await,
+                    "http://a" => Ok(response(1800, &["10.0.0.1:1"])),
+                    _ => {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        Ok(response(1800, &["10.0.0.2:2"]))
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        // The second tier was announced to while the first waited on its dead tracker
+        assert!(started.elapsed() < Duration::from_millis(550));
+        assert_eq!(response.peers.0.len(), 2);
+        assert_eq!(list.tiers()[0], vec!["http://a", "http://hangs"]);
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_tracker_responds() {
+        let mut list = AnnounceList::new(tiers(&[&["http://a"], &["http://b"]]));
+        let result = list
+            .announce_with(|_| async { Err(eyre!("unreachable")) })
+            .await;
+        assert!(result.is_err());
+
+        let mut empty = AnnounceList::new(tiers(&[&[""], &[]]));
+        assert!(empty.is_empty());
+        assert!(empty
+            .announce_with(|_| async { Ok(response(1, &[])) })
+            .await
+            .is_err());
+    }
+
+    /// Announce `event` to a tracker answering with an id and intervals, returning the request
+    async fn announce_recording(
+        session: &mut TrackerSession,
+        event: Option<TrackerEvent>,
+    ) -> TrackerRequest {
+        let mut sent = None;
+        session
+            .announce_with(event, |_, request| {
+                sent = Some(request);
+                async {
+                    Ok(TrackerResponse {
+                        interval: 1800,
+                        min_interval: Some(60),
+                        tracker_id: Some("abc".to_string()),
+                        ..Default::default()
+                    })
+                }
+            })
+            .await
+            .unwrap();
+        sent.unwrap()
+    }
+
+    #[tokio::test]
+    async fn session_reports_events_and_stats() {
+        let stats = TorrentStats::new(1000);
+        let trackers = AnnounceList::new(tiers(&[&["http://a"]]));
+        let mut session = TrackerSession::new(trackers, [7; 20], stats.clone());
+
+        let mut sent = vec![announce_recording(&mut session, Some(TrackerEvent::Started)).await];
+
+        stats.record_download(1200);
+        stats.record_upload(300);
+        stats.record_piece(1000);
+        assert!(stats.is_complete());
+        sent.push(announce_recording(&mut session, Some(TrackerEvent::Completed)).await);
+
+        assert_eq!(sent[0].event, Some(TrackerEvent::Started));
+        assert_eq!(sent[0].left, 1000);
+        assert_eq!(sent[0].tracker_id, None);
+        assert_eq!(sent[1].event, Some(TrackerEvent::Completed));
+        assert_eq!(
+            (sent[1].downloaded, sent[1].uploaded, sent[1].left),
+            (1200, 300, 0)
+        );
+        // The tracker id of the first response is sent back
+        assert_eq!(sent[1].tracker_id.as_deref(), Some("abc"));
+        assert!(!session.pending_completed);
+
+        let last = session.last_announce.unwrap();
+        assert_eq!(session.next_announce(), last + Duration::from_secs(1800));
+        assert_eq!(session.earliest_announce(), last + Duration::from_secs(60));
+
+        let query = serde_urlencoded::to_string(session.request(None)).unwrap();
+        assert!(!query.contains("event"));
+        assert!(query.contains("trackerid=abc"));
+        let query =
+            serde_urlencoded::to_string(session.request(Some(TrackerEvent::Stopped))).unwrap();
+        assert!(query.contains("event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn session_retries_after_failed_announce() {
+        let trackers = AnnounceList::new(tiers(&[&["http://a"]]));
+        let mut session = TrackerSession::new(trackers, [7; 20], TorrentStats::new(0));
+        assert!(!session.pending_completed);
+
+        let result = session
+            .announce_with(None, |_, _| async {
+                Err(eyre!("tracker failure: unregistered"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            session.next_announce(),
+            session.last_announce.unwrap() + RETRY_INTERVAL
+        );
+    }
+
+    /// An HTTP tracker recording the request line of every announce. It answers with
+    /// `body`, or closes the connection without answering if there is none.
+    async fn spawn_http_tracker(body: Option<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = vec![0; 4096];
+                    let len = stream.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..len]);
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push(request.lines().next().unwrap_or_default().to_string());
+                    if let Some(body) = body {
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                            body.len()
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    fn events(requests: &Mutex<Vec<String>>) -> Vec<Option<&'static str>> {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                ["completed", "stopped"]
+                    .into_iter()
+                    .find(|event| request.contains(&format!("event={event}")))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn session_backs_off_after_failed_completed() {
+        let (url, requests) = spawn_http_tracker(None).await;
+        let stats = TorrentStats::new(10);
+        let session =
+            TrackerSession::new(AnnounceList::new(tiers(&[&[&url]])), [7; 20], stats.clone());
+        stats.record_piece(10);
+
+        let handle = session.spawn(mpsc::unbounded_channel().0);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        handle.stop().await;
+
+        // One attempt, then nothing until the retry interval or stopping
+        assert_eq!(
+            events(&requests),
+            [Some("completed"), Some("completed"), Some("stopped")]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_sends_completed_before_stopping() {
+        let (url, requests) =
+            spawn_http_tracker(Some("d8:intervali1800e12:min intervali60e5:peers0:e")).await;
+        let stats = TorrentStats::new(10);
+        let session =
+            TrackerSession::new(AnnounceList::new(tiers(&[&[&url]])), [7; 20], stats.clone());
+
+        let handle = session.spawn(mpsc::unbounded_channel().0);
+        while requests.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Finishing within `min interval` of the last announce and stopping right away
+        stats.record_piece(10);
+        handle.stop().await;
+
+        assert_eq!(
+            events(&requests),
+            [None, Some("completed"), Some("stopped")]
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_signal_completion() {
+        let stats = TorrentStats::new(10);
+        stats.record_piece(4);
+        assert_eq!(stats.left(), 6);
+
+        let waiter = tokio::spawn({
+            let stats = stats.clone();
+            async move { stats.wait_complete().await }
+        });
+        stats.record_piece(8);
+        assert_eq!(stats.left(), 0);
+        timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn merges_ipv6_peers() {
+        let mut response = b"d8:intervali900e5:peers6:".to_vec();
+        response.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        response.extend(b"6:peers618:");
+        response.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        response.extend([0x1a, 0xe2]);
+        response.push(b'e');
+
+        let response: TrackerResponse = serde_bencode::from_bytes(&response).unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+        ];
+        assert_eq!(response.peers.0, expected);
+
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(b"d6:peers66:123456e").is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_peer_dictionaries() {
+        let mut response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali900e5:peersl\
+              d2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+              d2:ip11:2001:db8::14:porti6882ee\
+              d2:ip9:localhost7:peer id20:-YY0001-0123456789ab4:porti6883ee\
+              ee",
+        )
+        .unwrap();
+
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        assert_eq!(
+            response.peers.0,
+            vec![first, "[2001:db8::1]:6882".parse().unwrap()]
+        );
+        assert_eq!(&response.peer_ids[&first], b"-XX0001-0123456789ab");
+        assert_eq!(response.peer_ids.len(), 1);
+        assert_eq!(response.hostnames.len(), 1);
+
+        response.resolve_hostnames().await;
+        assert!(response.hostnames.is_empty());
+        let localhost = response.peers.0[2];
+        assert!(localhost.ip().is_loopback());
+        assert_eq!(localhost.port(), 6883);
+        assert_eq!(&response.peer_ids[&localhost], b"-YY0001-0123456789ab");
+    }
+
+    #[test]
+    fn parses_failure_and_warning_responses() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d14:failure reason12:unregisterede").unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("unregistered"));
+        assert!(response.peers.0.is_empty());
+
+        let response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali900e12:min intervali60e5:peers0:10:tracker id2:xy15:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.tracker_id.as_deref(), Some("xy"));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Context, ContextCompat, Result};
+use tokio::{net::UdpSocket, time::timeout};
+use url::{Host, Url};
+
+use crate::{Peers, ScrapeStats, TrackerEvent, TrackerRequest, TrackerResponse};
+
+/// Magic constant identifying the UDP tracker protocol in a connect request
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+
+/// A connection ID may be used for one minute after it was received
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The base of the retransmission schedule, a request is resent after 15 * 2 ^ n seconds
+pub const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The spec stops retransmitting once n reaches 8 (3840 seconds)
+pub const MAX_RETRANSMISSIONS: u32 = 8;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Info hashes per scrape request, more do not fit into a single packet
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Client side of the UDP tracker protocol
+/// https://www.bittorrent.org/beps/bep_0015.html
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+}
+
+impl UdpTracker {
+    /// Resolve the tracker host of a `udp://host:port[/path]` announce URL and bind a socket to it
+    pub async fn connect(announce: &str) -> Result<Self> {
+        let url = Url::parse(announce).context("parse UDP announce URL")?;
+        if url.scheme() != "udp" {
+            return Err(eyre!("Invalid UDP announce URL: {announce}"));
+        }
+        // IPv6 literals come in brackets, which the resolver does not take
+        let host = match url.host().context("missing host in UDP announce URL")? {
+            Host::Ipv6(ip) => ip.to_string(),
+            host => host.to_string(),
+        };
+        let port = url.port().context("missing port in UDP announce URL")?;
+        let addr = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .context("resolve UDP tracker")?
+            .next()
+            .context("UDP tracker host has no address")?;
+
+        UdpTracker::connect_addr(addr).await
+    }
+
+    /// Bind a socket to an already resolved tracker address
+    pub async fn connect_addr(addr: SocketAddr) -> Result<Self> {
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .context("bind UDP socket")?;
+        socket.connect(addr).await.context("connect UDP socket")?;
+
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+        })
+    }
+
+    /// Override the retransmission schedule, mostly useful to keep tests fast
+    pub fn with_timeouts(mut self, base_timeout: Duration, max_retransmissions: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &TrackerRequest,
+        info_hash: &[u8; 20],
+    ) -> Result<TrackerResponse> {
+        let mut attempt = 0;
+
+        loop {
+            // The connection ID is fetched again on each retry in case it expired while waiting
+            let connection_id = self.connection_id(&mut attempt).await?;
+            let transaction_id = rand::random::<u32>();
+            let packet = announce_packet(connection_id, transaction_id, request, info_hash)?;
+
+            if let Some(response) = self
+                .exchange(&packet, transaction_id, ACTION_ANNOUNCE, attempt)
+                .await?
+            {
+                // Trackers reached over IPv6 answer with IPv6 peers
+                let ipv6 = self.socket.peer_addr().is_ok_and(|addr| addr.is_ipv6());
+                return parse_announce_response(&response, ipv6);
+            }
+
+            attempt = self.next_attempt(attempt)?;
+        }
+    }
+
+    /// Scrape the swarms of `info_hashes`, the stats are in the same order.
+    /// More than 74 hashes are split over several requests.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            stats.extend(self.scrape_chunk(chunk).await?);
+        }
+        Ok(stats)
+    }
+
+    async fn scrape_chunk(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let mut attempt = 0;
+
+        loop {
+            let connection_id = self.connection_id(&mut attempt).await?;
+            let transaction_id = rand::random::<u32>();
+
+            // scrape request: <connection_id><action=2><transaction_id><info_hash>...
+            let mut packet = [
+                connection_id.to_be_bytes().as_slice(),
+                ACTION_SCRAPE.to_be_bytes().as_slice(),
+                transaction_id.to_be_bytes().as_slice(),
+            ]
+            .concat();
+            packet.extend(info_hashes.iter().flatten());
+
+            if let Some(response) = self
+                .exchange(&packet, transaction_id, ACTION_SCRAPE, attempt)
+                .await?
+            {
+                return parse_scrape_response(&response, info_hashes.len());
+            }
+
+            attempt = self.next_attempt(attempt)?;
+        }
+    }
+
+    async fn connection_id(&mut self, attempt: &mut u32) -> Result<u64> {
+        if let Some((connection_id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+            tracing::debug!("UDP tracker connection ID expired");
+            self.connection = None;
+        }
+
+        loop {
+            let transaction_id = rand::random::<u32>();
+
+            // connect request: <protocol_id><action=0><transaction_id>
+            let packet = [
+                PROTOCOL_ID.to_be_bytes().as_slice(),
+                ACTION_CONNECT.to_be_bytes().as_slice(),
+                transaction_id.to_be_bytes().as_slice(),
+            ]
+            .concat();
+
+            if let Some(response) = self
+                .exchange(&packet, transaction_id, ACTION_CONNECT, *attempt)
+                .await?
+            {
+                // connect response: <action=0><transaction_id><connection_id>
+                if response.len() < 16 {
+                    return Err(eyre!(
+                        "connect response too short: {} bytes",
+                        response.len()
+                    ));
+                }
+                let connection_id = read_u64(&response, 8);
+                self.connection = Some((connection_id, Instant::now()));
+                return Ok(connection_id);
+            }
+
+            *attempt = self.next_attempt(*attempt)?;
+        }
+    }
+
+    fn next_attempt(&self, attempt: u32) -> Result<u32> {
+        if attempt >= self.max_retransmissions {
+            return Err(eyre!(
+                "UDP tracker did not respond after {} retransmissions",
+                attempt
+            ));
+        }
+        Ok(attempt + 1)
+    }
+
+    /// Send a packet and wait 15 * 2 ^ attempt seconds for the matching response.
+    /// Returns `None` on timeout so that the caller can retransmit.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        transaction_id: u32,
+        action: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        self.socket
+            .send(packet)
+            .await
+            .context("send UDP tracker request")?;
+
+        let wait = self.base_timeout * 2u32.pow(attempt);
+        let response = timeout(wait, async {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let len = self
+                    .socket
+                    .recv(&mut buf)
+                    .await
+                    .context("receive UDP tracker response")?;
+                let response = &buf[..len];
+
+                // Ignore anything that isn't an answer to this request
+                if len < 8 || read_u32(response, 4) != transaction_id {
+                    continue;
+                }
+
+                let response_action = read_u32(response, 0);
+                if response_action == ACTION_ERROR {
+                    let message = String::from_utf8_lossy(&response[8..]);
+                    return Err(eyre!("UDP tracker error: {message}"));
+                }
+                if response_action != action {
+                    return Err(eyre!(
+                        "unexpected UDP tracker action {response_action}, expected {action}"
+                    ));
+                }
+
+                return Ok(response.to_vec());
+            }
+        })
+        .await;
+
+        match response {
+            Ok(response) => response.map(Some),
+            Err(_) => {
+                tracing::debug!("UDP tracker timed out after {:?}", wait);
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn announce_packet(
+    connection_id: u64,
+    transaction_id: u32,
+    request: &TrackerRequest,
+    info_hash: &[u8; 20],
+) -> Result<Vec<u8>> {
+    let peer_id: [u8; 20] = request
+        .peer_id
+        .as_bytes()
+        .try_into()
+        .map_err(|_| eyre!("peer id must be 20 bytes"))?;
+
+    // announce request: <connection_id><action=1><transaction_id><info_hash><peer_id>
+    // <downloaded><left><uploaded><event><ip><key><num_want><port>
+    Ok([
+        connection_id.to_be_bytes().as_slice(),
+        ACTION_ANNOUNCE.to_be_bytes().as_slice(),
+        transaction_id.to_be_bytes().as_slice(),
+        info_hash.as_slice(),
+        peer_id.as_slice(),
+        (request.downloaded as u64).to_be_bytes().as_slice(),
+        (request.left as u64).to_be_bytes().as_slice(),
+        (request.uploaded as u64).to_be_bytes().as_slice(),
+        TrackerEvent::udp_code(request.event)
+            .to_be_bytes()
+            .as_slice(),
+        0u32.to_be_bytes().as_slice(),
+        rand::random::<u32>().to_be_bytes().as_slice(),
+        (-1i32).to_be_bytes().as_slice(),
+        request.port.to_be_bytes().as_slice(),
+    ]
+    .concat())
+}
+
+fn parse_announce_response(response: &[u8], ipv6: bool) -> Result<TrackerResponse> {
+    // announce response: <action=1><transaction_id><interval><leechers><seeders><peers...>
+    if response.len() < 20 {
+        return Err(eyre!(
+            "announce response too short: {} bytes",
+            response.len()
+        ));
+    }
+
+    let interval = read_u32(response, 8) as usize;
+    let peers = if ipv6 {
+        Peers::from_compact6(&response[20..])?
+    } else {
+        Peers::from_compact(&response[20..])?
+    };
+
+    Ok(TrackerResponse {
+        interval,
+        peers,
+        ..Default::default()
+    })
+}
+
+fn parse_scrape_response(response: &[u8], count: usize) -> Result<Vec<ScrapeStats>> {
+    // scrape response: <action=2><transaction_id>, then per info hash
+    // <seeders><completed><leechers>
+    if response.len() < 8 + 12 * count {
+        return Err(eyre!(
+            "scrape response too short for {} torrents: {} bytes",
+            count,
+            response.len()
+        ));
+    }
+
+    Ok(response[8..]
+        .chunks_exact(12)
+        .take(count)
+        .map(|chunk| ScrapeStats {
+            seeders: read_u32(chunk, 0) as usize,
+            completed: read_u32(chunk, 4) as usize,
+            leechers: read_u32(chunk, 8) as usize,
+        })
+        .collect())
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    /// In-process stand-in for a UDP tracker, answering connect, announce and scrape requests
+    struct FakeTracker {
+        addr: SocketAddr,
+        connects: Arc<AtomicUsize>,
+        announces: Arc<AtomicUsize>,
+    }
+
+    impl FakeTracker {
+        /// Start a tracker that ignores the first `drop` packets it receives
+        async fn spawn(peers: Vec<SocketAddr>, drop: usize) -> Self {
+            FakeTracker::spawn_on("127.0.0.1:0", peers, drop).await
+        }
+
+        async fn spawn_on(bind: &str, peers: Vec<SocketAddr>, drop: usize) -> Self {
+            let socket = UdpSocket::bind(bind).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let connects = Arc::new(AtomicUsize::new(0));
+            let announces = Arc::new(AtomicUsize::new(0));
+
+            let (task_connects, task_announces) = (connects.clone(), announces.clone());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 2048];
+                let mut received = 0;
+                let mut next_connection_id = 1u64;
+                loop {
+                    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                    received += 1;
+                    if received <= drop {
+                        continue;
+                    }
+                    let packet = &buf[..len];
+                    let transaction_id = &packet[12..16];
+
+                    let response = if read_u64(packet, 0) == PROTOCOL_ID {
+                        task_connects.fetch_add(1, Ordering::SeqCst);
+                        let response = [
+                            ACTION_CONNECT.to_be_bytes().as_slice(),
+                            transaction_id,
+                            next_connection_id.to_be_bytes().as_slice(),
+                        ]
+                        .concat();
+                        next_connection_id += 1;
+                        response
+                    } else if read_u32(packet, 8) == ACTION_ANNOUNCE && len == 98 {
+                        task_announces.fetch_add(1, Ordering::SeqCst);
+                        let mut response = [
+                            ACTION_ANNOUNCE.to_be_bytes().as_slice(),
+                            transaction_id,
+                            1800u32.to_be_bytes().as_slice(),
+                            0u32.to_be_bytes().as_slice(),
+                            (peers.len() as u32).to_be_bytes().as_slice(),
+                        ]
+                        .concat();
+                        response.extend(Peers(peers.clone()).to_compact());
+                        response.extend(Peers(peers.clone()).to_compact6());
+                        response
+                    } else if read_u32(packet, 8) == ACTION_SCRAPE {
+                        // Every torrent has as many seeders as its first hash byte
+                        let mut response =
+                            [ACTION_SCRAPE.to_be_bytes().as_slice(), transaction_id].concat();
+                        for info_hash in packet[16..].chunks_exact(20) {
+                            response.extend((info_hash[0] as u32).to_be_bytes());
+                            response.extend(100u32.to_be_bytes());
+                            response.extend(2u32.to_be_bytes());
+                        }
+                        response
+                    } else {
+                        [
+                            ACTION_ERROR.to_be_bytes().as_slice(),
+                            transaction_id,
+                            b"bad request".as_slice(),
+                        ]
+                        .concat()
+                    };
+
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            });
+
+            Self {
+                addr,
+                connects,
+                announces,
+            }
+        }
+    }
+
+    fn request() -> TrackerRequest {
+        TrackerRequest {
+            peer_id: String::from("00112233445566778899"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 92063,
+            compact: 1,
+            event: Some(TrackerEvent::Started),
+            tracker_id: None,
+            ipv6: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_returns_peers() {
+        let peers = vec![
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881)),
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 51413)),
+        ];
+        let tracker = FakeTracker::spawn(peers.clone(), 0).await;
+
+        let mut client = UdpTracker::connect(&format!("udp://{}/announce", tracker.addr))
+            .await
+            .unwrap();
+        let response = client.announce(&request(), &[7u8; 20]).await.unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.peers.0, peers);
+    }
+
+    #[tokio::test]
+    async fn announce_over_ipv6_returns_ipv6_peers() {
+        let peers = vec!["[2001:db8::1]:6881".parse().unwrap()];
+        let tracker = FakeTracker::spawn_on("[::1]:0", peers.clone(), 0).await;
+
+        let mut client = UdpTracker::connect(&format!("udp://{}/announce", tracker.addr))
+            .await
+            .unwrap();
+        let response = client.announce(&request(), &[7u8; 20]).await.unwrap();
+        assert_eq!(response.peers.0, peers);
+    }
+
+    #[tokio::test]
+    async fn reuses_connection_id_until_expired() {
+        let tracker = FakeTracker::spawn(vec![], 0).await;
+        let mut client = UdpTracker::connect_addr(tracker.addr).await.unwrap();
+
+        client.announce(&request(), &[7u8; 20]).await.unwrap();
+        client.announce(&request(), &[7u8; 20]).await.unwrap();
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+
+        let (connection_id, _) = client.connection.unwrap();
+        client.connection = Some((
+            connection_id,
+            Instant::now() - CONNECTION_ID_LIFETIME - Duration::from_secs(1),
+        ));
+        client.announce(&request(), &[7u8; 20]).await.unwrap();
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 2);
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retransmits_lost_requests() {
+        let tracker = FakeTracker::spawn(vec![], 2).await;
+        let mut client = UdpTracker::connect_addr(tracker.addr)
+            .await
+            .unwrap()
+            .with_timeouts(Duration::from_millis(20), 3);
+
+        let response = client.announce(&request(), &[7u8; 20]).await.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retransmissions() {
+        let tracker = FakeTracker::spawn(vec![], usize::MAX).await;
+        let mut client = UdpTracker::connect_addr(tracker.addr)
+            .await
+            .unwrap()
+            .with_timeouts(Duration::from_millis(5), 2);
+
+        assert!(client.announce(&request(), &[7u8; 20]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scrapes_many_torrents() {
+        let tracker = FakeTracker::spawn(vec![], 0).await;
+        let mut client = UdpTracker::connect_addr(tracker.addr).await.unwrap();
+
+        // Two requests, the second one reuses the connection
+        let info_hashes: Vec<[u8; 20]> =
+            (0..MAX_SCRAPE_HASHES as u8 + 6).map(|i| [i; 20]).collect();
+        let stats = client.scrape(&info_hashes).await.unwrap();
+
+        assert_eq!(stats.len(), info_hashes.len());
+        assert_eq!(
+            stats[77],
+            ScrapeStats {
+                seeders: 77,
+                leechers: 2,
+                completed: 100
+            }
+        );
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+    }
+}